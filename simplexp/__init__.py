@@ -29,9 +29,39 @@ ExprOpId_ToStr = 20
 ExprOpId_MeasureTextX = 21
 ExprOpId_MeasureTextY = 22
 ExprOpId_If = 23
+ExprOpId_Concat = 24
+ExprOpId_StrLen = 25
+ExprOpId_Substr = 26
+ExprOpId_Format = 27
+
+def _last_error() -> str:
+    vec = _lib.simplexp_last_error()
+    message = str(_ffi.buffer(vec.ptr, vec.len)[:], 'utf8')
+    _lib.simplexp_str_free(vec)
+    return message
+
+
+FLOAT_MODE_FAST_MATH = 0
+FLOAT_MODE_STRICT = 1
+
+
+def set_float_mode(mode: int):
+    assert _lib.simplexp_set_float_mode(mode), 'Unknown float mode'
+
+
+class EvalError(Exception):
+    """Why an oplist entry failed to evaluate, e.g. a division by zero."""
+
+
+def _decode_value(value):
+    if isinstance(value, dict):
+        return {'inf': math.inf, 'negInf': -math.inf}[value['type']]
+    return value
 
 
 class Oplist:
+    _INVALID_ID = 2 ** 64 - 1
+
     def __init__(self, initial_expr: Optional[Expr | int | float | str] = None):
         self._inner = _lib.simplexp_oplist_new()
         assert self._inner, 'Failed to create oplist'
@@ -47,12 +77,215 @@ class Oplist:
         if self._inner:
             _lib.simplexp_oplist_free(self._inner)
 
-    def to_list(self):
+    def append_named(self, name: str, expr: Expr | int | float | str) -> int:
+        expr = Expr.wrap(expr)
+        return _lib.simplexp_oplist_append_named(self._inner, name.encode('utf8'), expr._inner)
+
+    def set_output(self, name: str, op_id: int):
+        assert _lib.simplexp_oplist_set_output(self._inner, name.encode('utf8'), op_id), \
+            'Failed to set output: {}'.format(_last_error())
+
+    def output(self, name: str) -> Optional[int]:
+        op_id = _lib.simplexp_oplist_output(self._inner, name.encode('utf8'))
+        return None if op_id == Oplist._INVALID_ID else op_id
+
+    def append_from_arena(self, arena: Arena, node: int) -> int:
+        op_id = _lib.simplexp_oplist_append_arena(self._inner, arena._inner, node)
+        assert op_id != Oplist._INVALID_ID, 'Failed to append node: {}'.format(_last_error())
+        return op_id
+
+    @staticmethod
+    def from_list(ops: list) -> Oplist:
+        return Oplist.from_dict(dict(Oplist().to_dict(), ops=ops))
+
+    @staticmethod
+    def from_dict(oplist: dict) -> Oplist:
+        data = json.dumps(oplist).encode('utf8')
+        oplist = Oplist.__new__(Oplist)
+        oplist._inner = _lib.simplexp_oplist_deserialize(_ffi.from_buffer(data), len(data))
+        assert oplist._inner, 'Failed to deserialize oplist: {}'.format(_last_error())
+        return oplist
+
+    def expr(self, op_id: int) -> Expr:
+        inner = _lib.simplexp_oplist_expr(self._inner, op_id)
+        assert inner, 'No such oplist entry'
+        return Expr(inner)
+
+    def __len__(self) -> int:
+        return _lib.simplexp_oplist_len(self._inner)
+
+    def compact(self, roots: list[int]) -> list[Optional[int]]:
+        """Drops every entry the roots don't depend on, returning the new id of every old id,
+        or None for dropped entries."""
+        ids = _ffi.new('size_t[]', len(self))
+        assert _lib.simplexp_oplist_compact(self._inner, roots, len(roots), ids), 'No such oplist entry'
+        return [None if new_id == Oplist._INVALID_ID else new_id for new_id in ids]
+
+    def extract(self, roots: list[int]) -> tuple[Oplist, list[Optional[int]]]:
+        """Copies the entries the roots depend on into a new oplist, returning it and the id in it
+        of every entry, or None for entries that weren't copied."""
+        ids = _ffi.new('size_t[]', len(self))
+        extracted = Oplist.__new__(Oplist)
+        extracted._inner = _lib.simplexp_oplist_extract(self._inner, roots, len(roots), ids)
+        assert extracted._inner, 'No such oplist entry'
+        return extracted, [None if new_id == Oplist._INVALID_ID else new_id for new_id in ids]
+
+    def optimize(self) -> list[int]:
+        """Optimizes every entry again, returning the new id of every old id."""
+        ids = _ffi.new('size_t[]', len(self))
+        assert _lib.simplexp_oplist_optimize(self._inner, ids), 'Failed to optimize oplist'
+        return list(ids)
+
+    def set_metadata(self, metadata):
+        """Sets JSON-serializable metadata `to_dict` includes, or removes it if None."""
+        data = b'' if metadata is None else json.dumps(metadata).encode('utf8')
+        assert _lib.simplexp_oplist_set_metadata(self._inner, _ffi.from_buffer(data), len(data)), \
+            'Failed to set metadata: {}'.format(_last_error())
+
+    def serialize_hashes(self, enabled: bool = True):
+        """Sets whether `to_dict` includes the content hash of every entry, as hex strings."""
+        assert _lib.simplexp_oplist_set_serialize_hashes(self._inner, enabled)
+
+    def content_hash(self, op_id: int) -> str:
+        """The hash of the entry's expression, which is the same across runs."""
+        content_hash = _ffi.new('uint64_t *')
+        assert _lib.simplexp_oplist_content_hash(self._inner, op_id, content_hash), 'No such oplist entry'
+        return '{:016x}'.format(content_hash[0])
+
+    def find_content_hash(self, content_hash: str) -> Optional[int]:
+        op_id = _lib.simplexp_oplist_find_content_hash(self._inner, int(content_hash, 16))
+        return None if op_id == Oplist._INVALID_ID else op_id
+
+    def stats(self) -> dict:
+        vec = _lib.simplexp_oplist_stats(self._inner)
+        stats = json.loads(bytes(_ffi.buffer(vec.ptr, vec.len)))
+        _lib.simplexp_str_free(vec)
+        return stats
+
+    def levels(self) -> list[list[int]]:
+        """Groups the entries into levels, each entry in the level after the last level of its operands."""
+        vec = _lib.simplexp_oplist_levels(self._inner)
+        levels = json.loads(bytes(_ffi.buffer(vec.ptr, vec.len)))
+        _lib.simplexp_str_free(vec)
+        return levels
+
+    def evaluate(self, variables: Optional[dict] = None, threads: int = 1, measure_text=None) -> list:
+        """Evaluates every entry, returning its value, or an `EvalError` if it failed.
+        `measure_text(text, font_size)` returns the width and height of the text, and may be called
+        from several threads at once if `threads` is more than 1."""
+        variables = {name: Expr.to_dict(Expr.wrap(value)) for name, value in (variables or {}).items()}
+        data = json.dumps(variables).encode('utf8')
+
+        callback = _ffi.NULL
+        if measure_text is not None:
+            @_ffi.callback('void(const uint8_t *, uintptr_t, double, double *, double *)')
+            def callback(text, length, font_size, width, height):
+                width[0], height[0] = measure_text(str(_ffi.buffer(text, length)[:], 'utf8'), font_size)
+
+        vec = _lib.simplexp_oplist_evaluate(self._inner, _ffi.from_buffer(data), len(data), threads, callback)
+        assert vec.ptr, 'Failed to evaluate oplist: {}'.format(_last_error())
+        results = json.loads(bytes(_ffi.buffer(vec.ptr, vec.len)))
+        _lib.simplexp_str_free(vec)
+        return [_decode_value(result['value']) if 'value' in result else EvalError(result['error'])
+                for result in results]
+
+    def variable_uses(self) -> dict[str, list[int]]:
+        """Maps every variable to the ids of the entries that depend on it, directly or transitively."""
+        vec = _lib.simplexp_oplist_variable_uses(self._inner)
+        uses = json.loads(bytes(_ffi.buffer(vec.ptr, vec.len)))
+        _lib.simplexp_str_free(vec)
+        return uses
+
+    def variables(self) -> list[str]:
+        return list(self.variable_uses())
+
+    def validate(self) -> list[dict]:
+        """Returns every problem of the oplist, e.g. an entry whose operand is a later entry."""
+        vec = _lib.simplexp_oplist_validate(self._inner)
+        problems = json.loads(bytes(_ffi.buffer(vec.ptr, vec.len)))
+        _lib.simplexp_str_free(vec)
+        return problems
+
+    def merge(self, other: Oplist) -> list[int]:
+        """Inserts every entry and output of `other`, returning the id of every entry of `other`."""
+        ids = _ffi.new('size_t[]', len(other))
+        assert _lib.simplexp_oplist_merge(self._inner, other._inner, ids), 'Failed to merge oplists'
+        return list(ids)
+
+    def diff(self, new: Oplist) -> dict:
+        """Computes the patch that turns this oplist into `new`, see `apply_patch`."""
+        vec = _lib.simplexp_oplist_diff(self._inner, new._inner)
+        assert vec.ptr, 'Failed to diff oplists: {}'.format(_last_error())
+        patch = json.loads(bytes(_ffi.buffer(vec.ptr, vec.len)))
+        _lib.simplexp_str_free(vec)
+        return patch
+
+    def apply_patch(self, patch: dict):
+        data = json.dumps(patch).encode('utf8')
+        assert _lib.simplexp_oplist_apply_patch(self._inner, _ffi.from_buffer(data), len(data)), \
+            'Failed to apply patch: {}'.format(_last_error())
+
+    def to_dot(self, before: Optional[Oplist] = None, highlight_roots: bool = False) -> str:
+        """Draws the oplist as a Graphviz graph, filling the entries `before` doesn't have."""
+        before = _ffi.NULL if before is None else before._inner
+        vec = _lib.simplexp_oplist_to_dot(self._inner, before, highlight_roots)
+        dot = str(_ffi.buffer(vec.ptr, vec.len)[:], 'utf8')
+        _lib.simplexp_str_free(vec)
+        return dot
+
+    def to_dict(self):
         vec = _lib.simplexp_oplist_serialize(self._inner)
         deserialized = json.loads(bytes(_ffi.buffer(vec.ptr, vec.len)))
         _lib.simplexp_str_free(vec)
         return deserialized
 
+    def to_list(self):
+        return self.to_dict()['ops']
+
+
+class Arena:
+    """Stores expressions as nodes with int ids, which is much cheaper than creating `Expr`s.
+    Nodes are only optimized when converted with `to_expr`."""
+
+    _INVALID_NODE = 2 ** 32 - 1
+
+    def __init__(self):
+        self._inner = _lib.simplexp_arena_new()
+        assert self._inner, 'Failed to create arena'
+
+    def _check(self, node: int) -> int:
+        assert node != Arena._INVALID_NODE, 'Failed to add node: {}'.format(_last_error())
+        return node
+
+    def var(self, name: str) -> int:
+        return self._check(_lib.simplexp_arena_var(self._inner, name.encode('utf8')))
+
+    def literal(self, value: int | float | str) -> int:
+        if isinstance(value, float):
+            if math.isnan(value):
+                raise ValueError('Cannot encode NaN values')
+            return self._check(_lib.simplexp_arena_float(self._inner, value))
+        elif isinstance(value, int):
+            return self._check(_lib.simplexp_arena_int(self._inner, value))
+        elif isinstance(value, str):
+            value = value.encode('utf8')
+            return self._check(_lib.simplexp_arena_str(self._inner, _ffi.from_buffer(value), len(value)))
+        else:
+            raise ValueError('Cannot encode value of type {}'.format(type(value)))
+
+    def op(self, op_id: int, *children: int) -> int:
+        children = list(children) + [0] * (5 - len(children))
+        return self._check(_lib.simplexp_arena_op(self._inner, op_id, *children))
+
+    def to_expr(self, node: int) -> Expr:
+        expr = _lib.simplexp_arena_to_expr(self._inner, node)
+        assert expr, 'Failed to convert node: {}'.format(_last_error())
+        return Expr(expr)
+
+    def __del__(self):
+        if self._inner:
+            _lib.simplexp_arena_free(self._inner)
+
 
 class Expr:
     def __init__(self, value: Expr | int | float | str | _ffi.CData):
@@ -75,7 +308,7 @@ class Expr:
         else:
             raise ValueError('Cannot encode value of type {}'.format(type(value)))
 
-        assert self._inner, 'Failed to create expression'
+        assert self._inner, 'Failed to create expression: {}'.format(_last_error())
 
     @staticmethod
     def wrap(expr: Expr | int | float | str):
@@ -132,6 +365,12 @@ class Expr:
             ExprOpId_Fdiv, self._inner, other._inner, _ffi.NULL, _ffi.NULL, _ffi.NULL
         ))
 
+    def __pow__(self, other: Expr | int | float | str):
+        other = Expr.wrap(other)
+        return Expr(_lib.simplexp_op_new(
+            ExprOpId_Pow, self._inner, other._inner, _ffi.NULL, _ffi.NULL, _ffi.NULL
+        ))
+
     def __or__(self, other: Expr | int | float | str):
         other = Expr.wrap(other)
         return Expr(_lib.simplexp_op_new(
@@ -200,6 +439,35 @@ class Expr:
             ExprOpId_ToStr, self._inner, _ffi.NULL, _ffi.NULL, _ffi.NULL, _ffi.NULL
         ))
 
+    def concat(self, other: Expr | str):
+        other = Expr.wrap(other)
+        return Expr(_lib.simplexp_op_new(
+            ExprOpId_Concat, self._inner, other._inner, _ffi.NULL, _ffi.NULL, _ffi.NULL
+        ))
+
+    def str_len(self):
+        return Expr(_lib.simplexp_op_new(
+            ExprOpId_StrLen, self._inner, _ffi.NULL, _ffi.NULL, _ffi.NULL, _ffi.NULL
+        ))
+
+    def substr(self, start: Expr | int, end: Expr | int):
+        start = Expr.wrap(start)
+        end = Expr.wrap(end)
+        return Expr(_lib.simplexp_op_new(
+            ExprOpId_Substr, self._inner, start._inner, end._inner, _ffi.NULL, _ffi.NULL
+        ))
+
+    @staticmethod
+    def format(template: Expr | str, *args: Expr | int | float | str):
+        # Each format fills the next `{}` placeholder of the template
+        result = Expr.wrap(template)
+        for arg in args:
+            arg = Expr.wrap(arg)
+            result = Expr(_lib.simplexp_op_new(
+                ExprOpId_Format, result._inner, arg._inner, _ffi.NULL, _ffi.NULL, _ffi.NULL
+            ))
+        return result
+
     @staticmethod
     def measure_text_x(text: Expr | str, font_size: Expr | int | float):
         text = Expr.wrap(text)
@@ -225,6 +493,27 @@ class Expr:
             ExprOpId_If, cond._inner, t._inner, f._inner, _ffi.NULL, _ffi.NULL
         ))
 
+    def to_dot(self, before: Optional[Expr] = None, highlight_roots: bool = False) -> str:
+        """Draws the expression as a Graphviz graph, filling the nodes `before` doesn't have."""
+        before = _ffi.NULL if before is None else before._inner
+        vec = _lib.simplexp_expr_to_dot(self._inner, before, highlight_roots)
+        dot = str(_ffi.buffer(vec.ptr, vec.len)[:], 'utf8')
+        _lib.simplexp_str_free(vec)
+        return dot
+
+    def variables(self) -> list[str]:
+        """The sorted names of the variables the expression uses."""
+        vec = _lib.simplexp_expr_variables(self._inner)
+        variables = json.loads(bytes(_ffi.buffer(vec.ptr, vec.len)))
+        _lib.simplexp_str_free(vec)
+        return variables
+
+    def typecheck(self):
+        vec = _lib.simplexp_expr_typecheck(self._inner)
+        deserialized = json.loads(bytes(_ffi.buffer(vec.ptr, vec.len)))
+        _lib.simplexp_str_free(vec)
+        return deserialized
+
     def __str__(self):
         vec = _lib.simplexp_expr_format(self._inner)
         formatted = str(_ffi.buffer(vec.ptr, vec.len)[:], 'utf8')
@@ -289,8 +578,22 @@ class Expr:
                 return Expr.measure_text_x(Expr.from_dict(obj['text']), Expr.from_dict(obj['fontSize']))
             elif obj['type'] == 'measureTextY':
                 return Expr.measure_text_y(Expr.from_dict(obj['text']), Expr.from_dict(obj['fontSize']))
+            elif obj['type'] == 'if':
+                return Expr.if_(Expr.from_dict(obj['cond']), Expr.from_dict(obj['t']), Expr.from_dict(obj['f']))
+            elif obj['type'] == 'concat':
+                return Expr.from_dict(obj['a']).concat(Expr.from_dict(obj['b']))
+            elif obj['type'] == 'strLen':
+                return Expr.from_dict(obj['a']).str_len()
+            elif obj['type'] == 'substr':
+                return Expr.from_dict(obj['text']).substr(Expr.from_dict(obj['start']), Expr.from_dict(obj['end']))
+            elif obj['type'] == 'format':
+                return Expr.format(Expr.from_dict(obj['template']), Expr.from_dict(obj['a']))
             elif obj['type'] == 'inf':
                 return Expr(float('inf'))
+            elif obj['type'] == 'negInf':
+                return Expr(float('-inf'))
+            elif obj['type'] == 'rational':
+                return Expr(obj['num']) / Expr(obj['den'])
             else:
                 assert False, f'Unknown type: {obj["type"]}'
         elif isinstance(obj, (int, float, str)):
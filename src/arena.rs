@@ -0,0 +1,221 @@
+use crate::expressions::{self, ExprOpRef, ExprPart, ExprPartRef, NanError};
+use crate::interner;
+use crate::optimizer::{self, OptimizerOptions};
+use serde::Serialize;
+use std::collections::HashMap;
+use std::convert::TryFrom;
+use std::sync::Arc;
+
+/// The id of a node in an `Arena`.
+#[derive(Serialize, Debug, Clone, Copy, Hash, Eq, PartialEq, Ord, PartialOrd)]
+pub struct NodeId(pub u32);
+
+impl NodeId {
+    pub fn index(self) -> usize {
+        self.0 as usize
+    }
+}
+
+/// Expressions stored as a graph of nodes with `u32` ids, instead of a tree of `Arc`s.
+///
+/// Meant for building many expressions at once, e.g. a whole document: adding a node is a push
+/// into a single `Vec`. Operands are always added before the operations using them, so they have
+/// smaller ids. Identical nodes are stored once. Nodes are stored as given, `to_expr` converts
+/// them to an expression that can be optimized.
+#[derive(Clone, Debug, Default)]
+pub struct Arena {
+    nodes: Vec<ExprPartRef>,
+    nodes_set: HashMap<ExprPartRef, NodeId>,
+}
+
+impl Arena {
+    pub fn new() -> Self {
+        Arena::default()
+    }
+
+    pub fn with_capacity(capacity: usize) -> Self {
+        Arena {
+            nodes: Vec::with_capacity(capacity),
+            nodes_set: HashMap::with_capacity(capacity),
+        }
+    }
+
+    pub fn len(&self) -> usize {
+        self.nodes.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.nodes.is_empty()
+    }
+
+    pub fn get(&self, id: NodeId) -> &ExprPartRef {
+        &self.nodes[id.index()]
+    }
+
+    /// Adds a node whose operand ids are ids in this arena.
+    ///
+    /// # Panics
+    ///
+    /// Panics if an operand isn't in the arena, or if the arena is full.
+    pub fn add(&mut self, node: ExprPartRef) -> NodeId {
+        assert!(
+            node.children()
+                .iter()
+                .all(|&child| child < self.nodes.len()),
+            "operands must be added to the arena before the operations using them"
+        );
+
+        let nodes = &mut self.nodes;
+        *self.nodes_set.entry(node.clone()).or_insert_with(move || {
+            let id = NodeId(u32::try_from(nodes.len()).expect("the arena is full"));
+            nodes.push(node);
+            id
+        })
+    }
+
+    pub fn int(&mut self, value: i64) -> NodeId {
+        self.add(ExprPartRef::IntLiteral(value))
+    }
+
+    /// Adds a float literal, infinities become `INF` and `-INF`.
+    pub fn float(&mut self, value: f64) -> Result<NodeId, NanError> {
+        let expr = ExprPart::from_f64(value)?;
+        Ok(self.add(expr.to_expr_part_ref(|_| unreachable!())))
+    }
+
+    pub fn string(&mut self, value: &str) -> NodeId {
+        self.add(ExprPartRef::StringLiteral(value.to_string()))
+    }
+
+    pub fn var(&mut self, name: &str) -> NodeId {
+        self.add(ExprPartRef::Operation(ExprOpRef::Var {
+            name: name.to_string(),
+        }))
+    }
+
+    /// Adds an operation whose operand ids are ids in this arena, see `add`.
+    pub fn op(&mut self, op: ExprOpRef) -> NodeId {
+        self.add(ExprPartRef::Operation(op))
+    }
+
+    /// Adds every node of the expression, returning the id of its root.
+    pub fn from_expr(&mut self, expr: &ExprPart) -> NodeId {
+        let mut ids: HashMap<*const ExprPart, NodeId> = HashMap::new();
+        for node in expr.post_order() {
+            let node_ref = node.to_expr_part_ref(|child| ids[&Arc::as_ptr(child)].index());
+            let id = self.add(node_ref);
+            ids.insert(node, id);
+        }
+        ids[&(expr as *const ExprPart)]
+    }
+
+    /// The ids of every node `id` depends on, including itself, in increasing order.
+    /// Every node comes after its operands.
+    pub fn reachable(&self, id: NodeId) -> Vec<NodeId> {
+        expressions::reachable(&self.nodes, id.index())
+            .into_iter()
+            .map(|idx| NodeId(idx as u32))
+            .collect()
+    }
+
+    /// Converts the node into an interned expression.
+    pub fn to_expr(&self, id: NodeId) -> Arc<ExprPart> {
+        interner::intern(expressions::build_expr(&self.nodes, id.index(), |expr| {
+            expr
+        }))
+    }
+
+    /// Converts the node into an expression, optimizing every node like building it through the
+    /// C API would.
+    pub fn to_optimized_expr(&self, id: NodeId, options: &OptimizerOptions) -> Arc<ExprPart> {
+        expressions::build_expr(&self.nodes, id.index(), |expr| {
+            optimizer::optimize_with(expr, options)
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::operation_list::OperationList;
+    use crate::optimizer::FloatMode;
+
+    fn add(a: NodeId, b: NodeId) -> ExprOpRef {
+        ExprOpRef::Add {
+            a: a.index(),
+            b: b.index(),
+        }
+    }
+
+    #[test]
+    fn identical_nodes_are_stored_once() {
+        let mut arena = Arena::new();
+        let x = arena.var("x");
+        let one = arena.int(1);
+        let sum = arena.op(add(x, one));
+        assert_eq!(arena.var("x"), x);
+        assert_eq!(arena.int(1), one);
+        assert_eq!(arena.op(add(x, one)), sum);
+        assert_ne!(arena.float(1.0).unwrap(), one);
+        assert_ne!(arena.op(add(one, x)), sum);
+        assert_eq!(arena.len(), 5);
+    }
+
+    #[test]
+    #[should_panic(expected = "operands must be added to the arena before")]
+    fn operands_must_come_first() {
+        let mut arena = Arena::new();
+        let x = arena.var("x");
+        arena.op(add(x, NodeId(1)));
+    }
+
+    #[test]
+    fn reachable_nodes_come_after_operands() {
+        let mut arena = Arena::new();
+        let x = arena.var("x");
+        let y = arena.var("y");
+        let two = arena.int(2);
+        let product = arena.op(ExprOpRef::Mul {
+            a: x.index(),
+            b: two.index(),
+        });
+        let sum = arena.op(add(product, x));
+        assert_eq!(arena.reachable(sum), vec![x, two, product, sum]);
+        assert_eq!(arena.reachable(y), vec![y]);
+    }
+
+    #[test]
+    fn only_optimized_exprs_are_optimized() {
+        let mut arena = Arena::new();
+        let x = arena.var("x");
+        let zero = arena.int(0);
+        let sum = arena.op(add(x, zero));
+        let options = OptimizerOptions {
+            float_mode: FloatMode::FastMath,
+        };
+        assert!(matches!(
+            &*arena.to_expr(sum),
+            ExprPart::Operation(expressions::ExprOp::Add { .. })
+        ));
+        assert!(Arc::ptr_eq(
+            &arena.to_optimized_expr(sum, &options),
+            &arena.to_expr(x)
+        ));
+    }
+
+    #[test]
+    fn oplists_map_arena_ids() {
+        let mut arena = Arena::new();
+        let x = arena.var("x");
+        let y = arena.var("y");
+        let sum = arena.op(add(x, y));
+
+        let mut oplist = OperationList::new();
+        let existing_y = oplist.add(&arena.to_expr(y));
+        let id = oplist.add_from_arena(&arena, sum);
+        assert_eq!(*oplist.to_expr(id), *arena.to_expr(sum));
+        assert_eq!(oplist.add_from_arena(&arena, y), existing_y);
+        assert_eq!(oplist.add_from_arena(&arena, sum), id);
+        assert_eq!(oplist.ops.len(), 3);
+    }
+}
@@ -1,12 +1,22 @@
-use crate::expressions::{ExprOp, ExprOpId, ExprPart};
-use crate::operation_list::OperationList;
-use crate::optimizer;
+use crate::arena::{Arena, NodeId};
+use crate::dot::{self, DotOptions};
+use crate::evaluator::{self, Context, Value};
+use crate::expressions::{ExprOp, ExprOpId, ExprOpRef, ExprPart, ExprPartRef};
+use crate::interner;
+use crate::operation_list::{OperationList, OplistError};
+use crate::optimizer::{self, FloatMode, OptimizerOptions};
+use crate::patch::Patch;
+use crate::typecheck;
 use konst::{primitive::parse_u32, unwrap_ctx};
 use num_traits::FromPrimitive;
+use serde::Serialize;
+use std::cell::RefCell;
 use std::ffi::CStr;
+use std::fmt::Display;
 use std::os::raw::c_char;
-use std::panic::catch_unwind;
-use std::ptr::null;
+use std::panic::{self, UnwindSafe};
+use std::ptr::{null, null_mut};
+use std::sync::atomic::{AtomicI32, Ordering};
 use std::sync::{Arc, Mutex};
 
 #[no_mangle]
@@ -16,15 +26,78 @@ pub static SIMPLEXP_VERSION_MINOR: u32 = unwrap_ctx!(parse_u32(env!("CARGO_PKG_V
 #[no_mangle]
 pub static SIMPLEXP_VERSION_PATCH: u32 = unwrap_ctx!(parse_u32(env!("CARGO_PKG_VERSION_PATCH")));
 
+thread_local! {
+    static LAST_ERROR: RefCell<Option<String>> = const { RefCell::new(None) };
+}
+
+fn set_last_error(err: impl Display) {
+    LAST_ERROR.with(|last_error| *last_error.borrow_mut() = Some(err.to_string()));
+}
+
+/// `panic::catch_unwind`, which also sets the last error to the panic message, so that failures
+/// from panics are reported like any other.
+fn catch_unwind<T>(f: impl FnOnce() -> T + UnwindSafe) -> std::thread::Result<T> {
+    panic::catch_unwind(f).inspect_err(|payload| {
+        let message = match payload.downcast_ref::<&str>() {
+            Some(message) => message,
+            None => payload
+                .downcast_ref::<String>()
+                .map_or("unknown panic", String::as_str),
+        };
+        set_last_error(format!("panicked: {}", message));
+    })
+}
+
+/// Returns the message of the last error reported on this thread, or an empty string.
+#[no_mangle]
+pub extern "C" fn simplexp_last_error() -> VecInner {
+    catch_unwind(|| {
+        let message = LAST_ERROR.with(|last_error| last_error.borrow().clone());
+        let (ptr, len, cap) = message.unwrap_or_default().into_bytes().into_raw_parts();
+        VecInner {
+            ptr: ptr as *const u8,
+            len,
+            cap,
+        }
+    })
+    .unwrap_or(VecInner {
+        ptr: null(),
+        len: 0,
+        cap: 0,
+    })
+}
+
+static FLOAT_MODE: AtomicI32 = AtomicI32::new(FloatMode::FastMath as i32);
+
+fn optimizer_options() -> OptimizerOptions {
+    OptimizerOptions {
+        float_mode: FromPrimitive::from_i32(FLOAT_MODE.load(Ordering::Relaxed)).unwrap(),
+    }
+}
+
+/// Sets how float expressions are simplified by `simplexp_op_new`: 0 for fast-math, 1 for strict IEEE-754.
+/// Returns false if the mode is unknown.
+#[no_mangle]
+pub extern "C" fn simplexp_set_float_mode(mode: i32) -> bool {
+    catch_unwind(|| {
+        let mode: FloatMode = FromPrimitive::from_i32(mode).unwrap();
+        FLOAT_MODE.store(mode as i32, Ordering::Relaxed);
+        true
+    })
+    .unwrap_or(false)
+}
+
 /// Creates a new variable binding.
 #[no_mangle]
 pub extern "C" fn simplexp_var_new(name: *const c_char) -> *const ExprPart {
     catch_unwind(|| {
         let name: &str = std::str::from_utf8(unsafe { CStr::from_ptr(name).to_bytes() }).unwrap();
 
-        Arc::into_raw(Arc::new(ExprPart::Operation(ExprOp::Var {
-            name: name.to_string(),
-        })))
+        Arc::into_raw(interner::intern(Arc::new(ExprPart::Operation(
+            ExprOp::Var {
+                name: name.to_string(),
+            },
+        ))))
     })
     .unwrap_or(null())
 }
@@ -45,24 +118,24 @@ pub extern "C" fn simplexp_op_new(
             ExprOp::from_ffi_children(op_id, child1, child2, child3, child4, child5)
         });
 
-        Arc::into_raw(optimizer::optimize(Arc::new(expr)))
+        Arc::into_raw(optimizer::optimize_with(
+            Arc::new(expr),
+            &optimizer_options(),
+        ))
     })
     .unwrap_or(null())
 }
 
 /// Creates a new float (f64) literal.
+/// Returns null for NaN, see `simplexp_last_error`.
 #[no_mangle]
 pub extern "C" fn simplexp_float_new(value: f64) -> *const ExprPart {
-    catch_unwind(|| {
-        assert!(!value.is_nan());
-
-        let expr = if f64::is_finite(value) {
-            ExprPart::FloatLiteral(value)
-        } else {
-            ExprPart::Operation(ExprOp::Inf)
-        };
-
-        Arc::into_raw(Arc::new(expr))
+    catch_unwind(|| match ExprPart::from_f64(value) {
+        Ok(expr) => Arc::into_raw(interner::intern(Arc::new(expr))),
+        Err(err) => {
+            set_last_error(err);
+            null()
+        }
     })
     .unwrap_or(null())
 }
@@ -70,7 +143,8 @@ pub extern "C" fn simplexp_float_new(value: f64) -> *const ExprPart {
 /// Creates a new int (i64) literal.
 #[no_mangle]
 pub extern "C" fn simplexp_int_new(value: i64) -> *const ExprPart {
-    catch_unwind(|| Arc::into_raw(Arc::new(ExprPart::IntLiteral(value)))).unwrap_or(null())
+    catch_unwind(|| Arc::into_raw(interner::intern(Arc::new(ExprPart::IntLiteral(value)))))
+        .unwrap_or(null())
 }
 
 /// Creates a new string literal.
@@ -82,7 +156,9 @@ pub extern "C" fn simplexp_str_new(value: *const u8, length: usize) -> *const Ex
         }
         let string =
             unsafe { std::str::from_utf8(std::slice::from_raw_parts(value, length)) }.unwrap();
-        Arc::into_raw(Arc::new(ExprPart::StringLiteral(string.to_string())))
+        Arc::into_raw(interner::intern(Arc::new(ExprPart::StringLiteral(
+            string.to_string(),
+        ))))
     })
     .unwrap_or(null())
 }
@@ -116,11 +192,95 @@ pub extern "C" fn simplexp_expr_format(expr: *const ExprPart) -> VecInner {
 
 /// Serialize an expression into a JSON string.
 /// The format is `{(op), "a": {(op), ...}}`
+/// Returns a null string if the expression contains NaN, see `simplexp_last_error`.
 #[no_mangle]
 pub extern "C" fn simplexp_expr_serialize(expr: *const ExprPart) -> VecInner {
     catch_unwind(|| {
         let expr = unsafe { expr.as_ref().unwrap() };
-        let (ptr, len, cap) = serde_json::to_vec(expr).unwrap().into_raw_parts();
+        let (ptr, len, cap) = match serde_json::to_vec(expr) {
+            Ok(json) => json.into_raw_parts(),
+            Err(err) => {
+                set_last_error(err);
+                (null_mut(), 0, 0)
+            }
+        };
+        VecInner {
+            ptr: ptr as *const u8,
+            len,
+            cap,
+        }
+    })
+    .unwrap_or(VecInner {
+        ptr: null(),
+        len: 0,
+        cap: 0,
+    })
+}
+
+/// Draws an expression in the Graphviz DOT language.
+/// If `before` isn't null, the nodes it doesn't have are filled, e.g. to show what optimizing it
+/// changed. If `highlight_roots` is true, the expression itself is drawn in bold.
+#[no_mangle]
+pub extern "C" fn simplexp_expr_to_dot(
+    expr: *const ExprPart,
+    before: *const ExprPart,
+    highlight_roots: bool,
+) -> VecInner {
+    catch_unwind(|| {
+        assert!(!expr.is_null());
+        let expr = unsafe { Arc::clone_from_ptr(expr) };
+        let before = (!before.is_null()).then(|| unsafe { Arc::clone_from_ptr(before) });
+        let options = DotOptions {
+            highlight_roots,
+            changed_since: before.as_ref(),
+        };
+        let (ptr, len, cap) = dot::expr_to_dot(&expr, &options)
+            .into_bytes()
+            .into_raw_parts();
+        VecInner {
+            ptr: ptr as *const u8,
+            len,
+            cap,
+        }
+    })
+    .unwrap_or(VecInner {
+        ptr: null(),
+        len: 0,
+        cap: 0,
+    })
+}
+
+/// Typechecks an expression into a JSON string.
+/// The format is `{"type": (type), "errors": [{"expr": (formatted expr), "message": (message)}, ...]}`
+#[no_mangle]
+pub extern "C" fn simplexp_expr_typecheck(expr: *const ExprPart) -> VecInner {
+    catch_unwind(|| {
+        let expr = unsafe { expr.as_ref().unwrap() };
+        let (ptr, len, cap) = serde_json::to_vec(&typecheck::typecheck(expr))
+            .unwrap()
+            .into_raw_parts();
+        VecInner {
+            ptr: ptr as *const u8,
+            len,
+            cap,
+        }
+    })
+    .unwrap_or(VecInner {
+        ptr: null(),
+        len: 0,
+        cap: 0,
+    })
+}
+
+/// Lists the variables of an expression into a JSON string.
+/// The format is `[(name), ...]`, sorted.
+#[no_mangle]
+pub extern "C" fn simplexp_expr_variables(expr: *const ExprPart) -> VecInner {
+    catch_unwind(|| {
+        let expr = unsafe { expr.as_ref().unwrap() };
+        let (ptr, len, cap) = serde_json::to_vec(&expr.variables())
+            .unwrap()
+            .into_raw_parts();
         VecInner {
             ptr: ptr as *const u8,
             len,
@@ -153,15 +313,77 @@ pub extern "C" fn simplexp_oplist_append(oplist: *const (), expr: *const ExprPar
     .unwrap_or(usize::MAX)
 }
 
+/// Append an expression to an operation list as the output named `name`, replacing the output
+/// previously named so. Returns the expression's id in the oplist.
+#[no_mangle]
+pub extern "C" fn simplexp_oplist_append_named(
+    oplist: *const (),
+    name: *const c_char,
+    expr: *const ExprPart,
+) -> usize {
+    catch_unwind(|| {
+        let name: &str = std::str::from_utf8(unsafe { CStr::from_ptr(name).to_bytes() }).unwrap();
+        let expr = unsafe { expr.as_ref().unwrap() };
+        let oplist = unsafe { (oplist as *const Mutex<OperationList>).as_ref().unwrap() };
+
+        oplist.lock().unwrap().add_output(name, expr)
+    })
+    .unwrap_or(usize::MAX)
+}
+
+/// Names an entry of an oplist, replacing the output previously named `name`.
+/// Returns false if there's no such entry, see `simplexp_last_error`.
+#[no_mangle]
+pub extern "C" fn simplexp_oplist_set_output(
+    oplist: *const (),
+    name: *const c_char,
+    id: usize,
+) -> bool {
+    catch_unwind(|| {
+        let name: &str = std::str::from_utf8(unsafe { CStr::from_ptr(name).to_bytes() }).unwrap();
+        let oplist = unsafe { (oplist as *const Mutex<OperationList>).as_ref().unwrap() };
+
+        match oplist.lock().unwrap().set_output(name, id) {
+            Ok(()) => true,
+            Err(err) => {
+                set_last_error(err);
+                false
+            }
+        }
+    })
+    .unwrap_or(false)
+}
+
+/// Returns the id of the output named `name`, or `usize::MAX` if there's no such output.
+#[no_mangle]
+pub extern "C" fn simplexp_oplist_output(oplist: *const (), name: *const c_char) -> usize {
+    catch_unwind(|| {
+        let name: &str = std::str::from_utf8(unsafe { CStr::from_ptr(name).to_bytes() }).unwrap();
+        let oplist = unsafe { (oplist as *const Mutex<OperationList>).as_ref().unwrap() };
+
+        let id = oplist.lock().unwrap().output(name);
+        id.unwrap_or(usize::MAX)
+    })
+    .unwrap_or(usize::MAX)
+}
+
 /// Serialize an oplist into a JSON string.
-/// The format is `[{(op), "a": (ref_id)}, ...]`
+/// The format is `{"formatVersion": 1, "simplexpVersion": "(version)", "opSet": {(op): [(field),
+/// ...], ...}, "ops": [{(op), "a": (ref_id)}, ...], "outputs": {(name): (ref_id), ...}}`,
+/// with `"metadata"` if set by `simplexp_oplist_set_metadata`, and `"hashes": [(hex content
+/// hash), ...]` if enabled by `simplexp_oplist_set_serialize_hashes`.
+/// Returns a null string if the oplist contains NaN, see `simplexp_last_error`.
 #[no_mangle]
 pub extern "C" fn simplexp_oplist_serialize(oplist: *const ()) -> VecInner {
     catch_unwind(|| {
         let oplist = unsafe { Arc::from_raw(oplist as *const Mutex<OperationList>) };
-        let (ptr, len, cap) = serde_json::to_vec(&*oplist.lock().unwrap())
-            .unwrap()
-            .into_raw_parts();
+        let (ptr, len, cap) = match serde_json::to_vec(&*oplist.lock().unwrap()) {
+            Ok(json) => json.into_raw_parts(),
+            Err(err) => {
+                set_last_error(err);
+                (null_mut(), 0, 0)
+            }
+        };
         let _ = Arc::into_raw(oplist);
         VecInner {
             ptr: ptr as *const u8,
@@ -176,7 +398,699 @@ pub extern "C" fn simplexp_oplist_serialize(oplist: *const ()) -> VecInner {
     })
 }
 
-/// Frees a string allocated by `simplexp_expr_format`, `simplexp_expr_serialize`, or `simplexp_oplist_serialize`.
+/// Deserialize an oplist from a JSON string, in the format of `simplexp_oplist_serialize`.
+/// Returns null if the JSON isn't a valid oplist, e.g. if an entry refers to a later entry, or if
+/// it was serialized by an incompatible version, see `simplexp_last_error`.
+#[no_mangle]
+pub extern "C" fn simplexp_oplist_deserialize(json: *const u8, length: usize) -> *const () {
+    catch_unwind(|| {
+        assert!(!json.is_null());
+        let json = unsafe { std::slice::from_raw_parts(json, length) };
+        match serde_json::from_slice::<OperationList>(json) {
+            Ok(oplist) => Arc::into_raw(Arc::new(Mutex::new(oplist))) as *const (),
+            Err(err) => {
+                set_last_error(err);
+                null()
+            }
+        }
+    })
+    .unwrap_or(null())
+}
+
+/// Converts an entry of an oplist into an expression, returning null if there's no such entry.
+/// The entry is converted as it is, without optimizing it.
+#[no_mangle]
+pub extern "C" fn simplexp_oplist_expr(oplist: *const (), id: usize) -> *const ExprPart {
+    catch_unwind(|| {
+        let oplist = unsafe { (oplist as *const Mutex<OperationList>).as_ref().unwrap() };
+        let oplist = oplist.lock().unwrap();
+        if id >= oplist.ops.len() {
+            return null();
+        }
+        Arc::into_raw(oplist.to_expr(id))
+    })
+    .unwrap_or(null())
+}
+
+/// Creates a new arena, which stores expressions as nodes with `u32` ids.
+/// Adding a node to an arena is much cheaper than creating an expression.
+#[no_mangle]
+pub extern "C" fn simplexp_arena_new() -> *const () {
+    catch_unwind(|| Arc::into_raw(Arc::new(Mutex::new(Arena::new()))) as *const ())
+        .unwrap_or(null())
+}
+
+fn with_arena<T>(arena: *const (), f: impl FnOnce(&mut Arena) -> T) -> T {
+    let arena = unsafe { (arena as *const Mutex<Arena>).as_ref().unwrap() };
+    f(&mut arena.lock().unwrap())
+}
+
+/// Whether `id` is a node of the arena, otherwise sets the last error.
+/// Checked before using ids, so that bad ids can't panic and poison the arena's lock.
+fn check_node(arena: &Arena, id: usize) -> bool {
+    let valid = id < arena.len();
+    if !valid {
+        set_last_error(format!("node {} isn't in the arena", id));
+    }
+    valid
+}
+
+/// Adds a variable binding to an arena, returning its node id.
+#[no_mangle]
+pub extern "C" fn simplexp_arena_var(arena: *const (), name: *const c_char) -> u32 {
+    catch_unwind(|| {
+        let name: &str = std::str::from_utf8(unsafe { CStr::from_ptr(name).to_bytes() }).unwrap();
+        with_arena(arena, |arena| arena.var(name).0)
+    })
+    .unwrap_or(u32::MAX)
+}
+
+/// Adds an int (i64) literal to an arena, returning its node id.
+#[no_mangle]
+pub extern "C" fn simplexp_arena_int(arena: *const (), value: i64) -> u32 {
+    catch_unwind(|| with_arena(arena, |arena| arena.int(value).0)).unwrap_or(u32::MAX)
+}
+
+/// Adds a float (f64) literal to an arena, returning its node id.
+/// Returns `u32::MAX` for NaN, see `simplexp_last_error`.
+#[no_mangle]
+pub extern "C" fn simplexp_arena_float(arena: *const (), value: f64) -> u32 {
+    catch_unwind(|| match with_arena(arena, |arena| arena.float(value)) {
+        Ok(id) => id.0,
+        Err(err) => {
+            set_last_error(err);
+            u32::MAX
+        }
+    })
+    .unwrap_or(u32::MAX)
+}
+
+/// Adds a string literal to an arena, returning its node id.
+#[no_mangle]
+pub extern "C" fn simplexp_arena_str(arena: *const (), value: *const u8, length: usize) -> u32 {
+    catch_unwind(|| {
+        assert!(!value.is_null());
+        let string =
+            unsafe { std::str::from_utf8(std::slice::from_raw_parts(value, length)) }.unwrap();
+        with_arena(arena, |arena| arena.string(string).0)
+    })
+    .unwrap_or(u32::MAX)
+}
+
+/// Adds an operation to an arena, returning its node id.
+/// The children are node ids in the same arena, unused children are ignored.
+/// Unlike `simplexp_op_new`, the operation is not optimized.
+/// Returns `u32::MAX` for unknown operations and children that aren't in the arena, see
+/// `simplexp_last_error`.
+#[no_mangle]
+pub extern "C" fn simplexp_arena_op(
+    arena: *const (),
+    op_id: i32,
+    child1: u32,
+    child2: u32,
+    child3: u32,
+    child4: u32,
+    child5: u32,
+) -> u32 {
+    catch_unwind(|| {
+        let Some(op_id) = ExprOpId::from_i32(op_id) else {
+            set_last_error(format!("unknown operation id {}", op_id));
+            return u32::MAX;
+        };
+        let node = ExprPartRef::Operation(ExprOpRef::from_children(
+            op_id,
+            child1 as usize,
+            child2 as usize,
+            child3 as usize,
+            child4 as usize,
+            child5 as usize,
+        ));
+        with_arena(arena, |arena| {
+            if !node
+                .children()
+                .into_iter()
+                .all(|child| check_node(arena, child))
+            {
+                return u32::MAX;
+            }
+            arena.add(node).0
+        })
+    })
+    .unwrap_or(u32::MAX)
+}
+
+/// Returns the number of entries in an oplist.
+#[no_mangle]
+pub extern "C" fn simplexp_oplist_len(oplist: *const ()) -> usize {
+    catch_unwind(|| {
+        let oplist = unsafe { (oplist as *const Mutex<OperationList>).as_ref().unwrap() };
+        let len = oplist.lock().unwrap().ops.len();
+        len
+    })
+    .unwrap_or(usize::MAX)
+}
+
+/// Drops every entry of an oplist that neither the `roots` nor the named outputs depend on, and
+/// renumbers the rest.
+/// If `ids` isn't null, the new id of every old id is written to it, or `usize::MAX` for dropped
+/// entries, so it must have room for `simplexp_oplist_len` ids.
+/// Returns false if a root isn't an entry, leaving the oplist as it was.
+#[no_mangle]
+pub extern "C" fn simplexp_oplist_compact(
+    oplist: *const (),
+    roots: *const usize,
+    root_count: usize,
+    ids: *mut usize,
+) -> bool {
+    catch_unwind(|| {
+        let oplist = unsafe { (oplist as *const Mutex<OperationList>).as_ref().unwrap() };
+        let roots = match root_count {
+            0 => &[],
+            _ => unsafe { std::slice::from_raw_parts(roots, root_count) },
+        };
+        let mut oplist = oplist.lock().unwrap();
+        if roots.iter().any(|&root| root >= oplist.ops.len()) {
+            return false;
+        }
+        let new_ids = oplist.compact(roots);
+        if !ids.is_null() {
+            let ids = unsafe { std::slice::from_raw_parts_mut(ids, new_ids.len()) };
+            for (id, new_id) in ids.iter_mut().zip(new_ids) {
+                *id = new_id.unwrap_or(usize::MAX);
+            }
+        }
+        true
+    })
+    .unwrap_or(false)
+}
+
+/// Creates a new oplist with the entries of an oplist that the `roots` depend on, including the
+/// roots, renumbered, along with the named outputs of those entries.
+/// If `ids` isn't null, the id in the new oplist of every entry of the oplist is written to it, or
+/// `usize::MAX` for entries that weren't copied, so it must have room for `simplexp_oplist_len`
+/// ids. Returns null if a root isn't an entry.
+#[no_mangle]
+pub extern "C" fn simplexp_oplist_extract(
+    oplist: *const (),
+    roots: *const usize,
+    root_count: usize,
+    ids: *mut usize,
+) -> *const () {
+    catch_unwind(|| {
+        let oplist = unsafe { (oplist as *const Mutex<OperationList>).as_ref().unwrap() };
+        let roots = match root_count {
+            0 => &[],
+            _ => unsafe { std::slice::from_raw_parts(roots, root_count) },
+        };
+        let oplist = oplist.lock().unwrap();
+        if roots.iter().any(|&root| root >= oplist.ops.len()) {
+            return null();
+        }
+        let (extracted, new_ids) = oplist.extract(roots);
+        if !ids.is_null() {
+            let ids = unsafe { std::slice::from_raw_parts_mut(ids, new_ids.len()) };
+            for (id, new_id) in ids.iter_mut().zip(new_ids) {
+                *id = new_id.unwrap_or(usize::MAX);
+            }
+        }
+        Arc::into_raw(Arc::new(Mutex::new(extracted))) as *const ()
+    })
+    .unwrap_or(null())
+}
+
+/// Optimizes every entry of an oplist again, merging entries that became equivalent.
+/// If `ids` isn't null, the new id of every old id is written to it, so it must have room for
+/// `simplexp_oplist_len` ids.
+#[no_mangle]
+pub extern "C" fn simplexp_oplist_optimize(oplist: *const (), ids: *mut usize) -> bool {
+    catch_unwind(|| {
+        let oplist = unsafe { (oplist as *const Mutex<OperationList>).as_ref().unwrap() };
+        let new_ids = oplist.lock().unwrap().optimize(&optimizer_options());
+        if !ids.is_null() {
+            let ids = unsafe { std::slice::from_raw_parts_mut(ids, new_ids.len()) };
+            ids.copy_from_slice(&new_ids);
+        }
+        true
+    })
+    .unwrap_or(false)
+}
+
+/// Validates an oplist into a JSON string, which is an empty list if the oplist is valid.
+/// The format is `[{"type": (problem), "message": (message), ...}, ...]`
+#[no_mangle]
+pub extern "C" fn simplexp_oplist_validate(oplist: *const ()) -> VecInner {
+    #[derive(Serialize)]
+    struct Diagnostic<'a> {
+        #[serde(flatten)]
+        error: &'a OplistError,
+        message: String,
+    }
+
+    catch_unwind(|| {
+        let oplist = unsafe { (oplist as *const Mutex<OperationList>).as_ref().unwrap() };
+        let errors = oplist.lock().unwrap().validate();
+        let diagnostics: Vec<Diagnostic> = errors
+            .iter()
+            .map(|error| Diagnostic {
+                error,
+                message: error.to_string(),
+            })
+            .collect();
+        let (ptr, len, cap) = serde_json::to_vec(&diagnostics).unwrap().into_raw_parts();
+        VecInner {
+            ptr: ptr as *const u8,
+            len,
+            cap,
+        }
+    })
+    .unwrap_or(VecInner {
+        ptr: null(),
+        len: 0,
+        cap: 0,
+    })
+}
+
+/// Inserts every entry and named output of `other` into an oplist, reusing identical entries.
+/// If `ids` isn't null, the id in the oplist of every entry of `other` is written to it, so it
+/// must have room for `simplexp_oplist_len(other)` ids.
+#[no_mangle]
+pub extern "C" fn simplexp_oplist_merge(
+    oplist: *const (),
+    other: *const (),
+    ids: *mut usize,
+) -> bool {
+    catch_unwind(|| {
+        let oplist_ref = unsafe { (oplist as *const Mutex<OperationList>).as_ref().unwrap() };
+        let other_ref = unsafe { (other as *const Mutex<OperationList>).as_ref().unwrap() };
+        let new_ids = {
+            let mut oplist_ref = oplist_ref.lock().unwrap();
+            if oplist == other {
+                let other = oplist_ref.clone();
+                oplist_ref.merge(&other)
+            } else {
+                oplist_ref.merge(&other_ref.lock().unwrap())
+            }
+        };
+        if !ids.is_null() {
+            let ids = unsafe { std::slice::from_raw_parts_mut(ids, new_ids.len()) };
+            ids.copy_from_slice(&new_ids);
+        }
+        true
+    })
+    .unwrap_or(false)
+}
+
+/// Lists the variables of an oplist into a JSON string.
+/// The format is `{(name): [(ref_id), ...], ...}`, mapping every variable to the entries that
+/// depend on it, directly or through their operands.
+#[no_mangle]
+pub extern "C" fn simplexp_oplist_variable_uses(oplist: *const ()) -> VecInner {
+    catch_unwind(|| {
+        let oplist = unsafe { (oplist as *const Mutex<OperationList>).as_ref().unwrap() };
+        let uses = oplist.lock().unwrap().variable_uses();
+        let (ptr, len, cap) = serde_json::to_vec(&uses).unwrap().into_raw_parts();
+        VecInner {
+            ptr: ptr as *const u8,
+            len,
+            cap,
+        }
+    })
+    .unwrap_or(VecInner {
+        ptr: null(),
+        len: 0,
+        cap: 0,
+    })
+}
+
+/// Groups the entries of an oplist into levels into a JSON string, each entry in the level after
+/// the last level of its operands. The format is `[[(ref_id), ...], ...]`
+#[no_mangle]
+pub extern "C" fn simplexp_oplist_levels(oplist: *const ()) -> VecInner {
+    catch_unwind(|| {
+        let oplist = unsafe { (oplist as *const Mutex<OperationList>).as_ref().unwrap() };
+        let levels = oplist.lock().unwrap().levels();
+        let (ptr, len, cap) = serde_json::to_vec(&levels).unwrap().into_raw_parts();
+        VecInner {
+            ptr: ptr as *const u8,
+            len,
+            cap,
+        }
+    })
+    .unwrap_or(VecInner {
+        ptr: null(),
+        len: 0,
+        cap: 0,
+    })
+}
+
+/// Measures text for `simplexp_oplist_evaluate`, writing the width and height of the UTF-8 text
+/// with the font size to `width` and `height`.
+pub type MeasureTextCallback = extern "C" fn(
+    text: *const u8,
+    length: usize,
+    font_size: f64,
+    width: *mut f64,
+    height: *mut f64,
+);
+
+/// Evaluates every entry of an oplist into a JSON string.
+/// `vars` is a JSON object with the value of every variable, `{(name): (literal), ...}`, where
+/// literals are in the format of `simplexp_expr_serialize`. `measure_text` may be null if no entry
+/// measures text. With more than one thread, entries that don't depend on each other are evaluated
+/// concurrently, so `measure_text` may be called from several threads at once.
+/// The format is `[{"value": (literal)}, {"error": (message)}, ...]` with an item for every entry.
+/// Returns a null string if `vars` is invalid, see `simplexp_last_error`.
+#[no_mangle]
+pub extern "C" fn simplexp_oplist_evaluate(
+    oplist: *const (),
+    vars: *const u8,
+    length: usize,
+    threads: usize,
+    measure_text: Option<MeasureTextCallback>,
+) -> VecInner {
+    #[derive(Serialize)]
+    #[serde(rename_all = "camelCase")]
+    enum EvalResult {
+        Value(ExprPart),
+        Error(String),
+    }
+
+    catch_unwind(|| {
+        assert!(!vars.is_null());
+        let vars = unsafe { std::slice::from_raw_parts(vars, length) };
+        let oplist = unsafe { (oplist as *const Mutex<OperationList>).as_ref().unwrap() };
+        let mut ctx = match context(vars) {
+            Ok(ctx) => ctx,
+            Err(err) => {
+                set_last_error(err);
+                return VecInner {
+                    ptr: null(),
+                    len: 0,
+                    cap: 0,
+                };
+            }
+        };
+        if let Some(measure_text) = measure_text {
+            ctx = ctx.with_text_measurer(move |text, font_size| {
+                let (mut width, mut height) = (0.0, 0.0);
+                measure_text(
+                    text.as_ptr(),
+                    text.len(),
+                    font_size,
+                    &mut width,
+                    &mut height,
+                );
+                (width, height)
+            });
+        }
+
+        let results = evaluator::evaluate_oplist(&oplist.lock().unwrap(), &ctx, threads);
+        let results: Vec<EvalResult> = results
+            .into_iter()
+            .map(|result| match result {
+                Ok(value) => EvalResult::Value(value.into()),
+                Err(err) => EvalResult::Error(err.to_string()),
+            })
+            .collect();
+        let (ptr, len, cap) = serde_json::to_vec(&results).unwrap().into_raw_parts();
+        VecInner {
+            ptr: ptr as *const u8,
+            len,
+            cap,
+        }
+    })
+    .unwrap_or(VecInner {
+        ptr: null(),
+        len: 0,
+        cap: 0,
+    })
+}
+
+/// Parses the variables of `simplexp_oplist_evaluate` into a context.
+fn context(vars: &[u8]) -> Result<Context, String> {
+    let vars: serde_json::Map<String, serde_json::Value> =
+        serde_json::from_slice(vars).map_err(|err| err.to_string())?;
+    let mut ctx = Context::new();
+    for (name, value) in vars {
+        let value = match ExprPartRef::from_json(value)? {
+            ExprPartRef::IntLiteral(v) => Value::Int(v),
+            ExprPartRef::FloatLiteral(v) => Value::Float(v.0),
+            ExprPartRef::StringLiteral(v) => Value::String(v),
+            ExprPartRef::RationalLiteral(v) => Value::Float(v.to_f64()),
+            ExprPartRef::Operation(ExprOpRef::Inf) => Value::Float(f64::INFINITY),
+            ExprPartRef::Operation(ExprOpRef::NegInf) => Value::Float(f64::NEG_INFINITY),
+            ExprPartRef::Operation(_) => {
+                return Err(format!("the value of {:?} must be a literal", name));
+            }
+        };
+        ctx = ctx.with_var(&name, value);
+    }
+    Ok(ctx)
+}
+
+/// Computes statistics of an oplist into a JSON string.
+/// The format is `{"entries": (count), "ops": {(type): (count), ...}, "variables": [(name), ...],
+/// "maxDepth": (depth), "dedupHitRate": (rate), ...}`
+#[no_mangle]
+pub extern "C" fn simplexp_oplist_stats(oplist: *const ()) -> VecInner {
+    catch_unwind(|| {
+        let oplist = unsafe { (oplist as *const Mutex<OperationList>).as_ref().unwrap() };
+        let stats = oplist.lock().unwrap().stats();
+        let (ptr, len, cap) = serde_json::to_vec(&stats).unwrap().into_raw_parts();
+        VecInner {
+            ptr: ptr as *const u8,
+            len,
+            cap,
+        }
+    })
+    .unwrap_or(VecInner {
+        ptr: null(),
+        len: 0,
+        cap: 0,
+    })
+}
+
+/// Sets the metadata `simplexp_oplist_serialize` includes, from a JSON string.
+/// An empty string removes the metadata. Returns false if the JSON is invalid.
+#[no_mangle]
+pub extern "C" fn simplexp_oplist_set_metadata(
+    oplist: *const (),
+    json: *const u8,
+    length: usize,
+) -> bool {
+    catch_unwind(|| {
+        assert!(!json.is_null());
+        let json = unsafe { std::slice::from_raw_parts(json, length) };
+        let oplist = unsafe { (oplist as *const Mutex<OperationList>).as_ref().unwrap() };
+        let metadata = if json.is_empty() {
+            None
+        } else {
+            match serde_json::from_slice(json) {
+                Ok(metadata) => Some(metadata),
+                Err(err) => {
+                    set_last_error(err);
+                    return false;
+                }
+            }
+        };
+        oplist.lock().unwrap().metadata = metadata;
+        true
+    })
+    .unwrap_or(false)
+}
+
+/// Sets whether `simplexp_oplist_serialize` includes the content hash of every entry.
+#[no_mangle]
+pub extern "C" fn simplexp_oplist_set_serialize_hashes(oplist: *const (), enabled: bool) -> bool {
+    catch_unwind(|| {
+        let oplist = unsafe { (oplist as *const Mutex<OperationList>).as_ref().unwrap() };
+        oplist.lock().unwrap().serialize_hashes = enabled;
+        true
+    })
+    .unwrap_or(false)
+}
+
+/// Writes the content hash of an entry of an oplist to `hash`, which only depends on the entry's
+/// expression, so it is the same across runs. Returns false if there's no such entry.
+#[no_mangle]
+pub extern "C" fn simplexp_oplist_content_hash(
+    oplist: *const (),
+    id: usize,
+    hash: *mut u64,
+) -> bool {
+    catch_unwind(|| {
+        assert!(!hash.is_null());
+        let oplist = unsafe { (oplist as *const Mutex<OperationList>).as_ref().unwrap() };
+        let hashes = oplist.lock().unwrap().content_hashes();
+        match hashes.get(id) {
+            Some(&content_hash) => {
+                unsafe { *hash = content_hash };
+                true
+            }
+            None => false,
+        }
+    })
+    .unwrap_or(false)
+}
+
+/// Returns the id of the first entry of an oplist with the content hash, or `usize::MAX` if
+/// there's no such entry.
+#[no_mangle]
+pub extern "C" fn simplexp_oplist_find_content_hash(oplist: *const (), hash: u64) -> usize {
+    catch_unwind(|| {
+        let oplist = unsafe { (oplist as *const Mutex<OperationList>).as_ref().unwrap() };
+        let id = oplist.lock().unwrap().find_content_hash(hash);
+        id.unwrap_or(usize::MAX)
+    })
+    .unwrap_or(usize::MAX)
+}
+
+/// Draws an oplist in the Graphviz DOT language, labeling entries with their named outputs.
+/// If `before` isn't null, the entries it doesn't have are filled. If `highlight_roots` is true,
+/// named outputs and entries no other entry uses are drawn in bold.
+#[no_mangle]
+pub extern "C" fn simplexp_oplist_to_dot(
+    oplist: *const (),
+    before: *const (),
+    highlight_roots: bool,
+) -> VecInner {
+    catch_unwind(|| {
+        let oplist_ref = unsafe { (oplist as *const Mutex<OperationList>).as_ref().unwrap() };
+        let oplist_ref = oplist_ref.lock().unwrap();
+        let before = match before.is_null() || before == oplist {
+            true => None,
+            false => {
+                let before = unsafe { (before as *const Mutex<OperationList>).as_ref().unwrap() };
+                let before = before.lock().unwrap().clone();
+                Some(before)
+            }
+        };
+        let options = DotOptions {
+            highlight_roots,
+            changed_since: before.as_ref(),
+        };
+        let (ptr, len, cap) = dot::oplist_to_dot(&oplist_ref, &options)
+            .into_bytes()
+            .into_raw_parts();
+        VecInner {
+            ptr: ptr as *const u8,
+            len,
+            cap,
+        }
+    })
+    .unwrap_or(VecInner {
+        ptr: null(),
+        len: 0,
+        cap: 0,
+    })
+}
+
+/// Computes the patch that turns the `old` oplist into the `new` one, as a JSON string.
+/// The format is `{"removed": [[(start), (end)], ...], "appended": [{(op), "a": (ref_id)}, ...],
+/// "outputs": {(name): (ref_id) | null}, "metadata": (metadata), "serializeHashes": true}`, where
+/// empty fields are omitted.
+/// Returns a null string if the new oplist contains NaN, see `simplexp_last_error`.
+#[no_mangle]
+pub extern "C" fn simplexp_oplist_diff(old: *const (), new: *const ()) -> VecInner {
+    catch_unwind(|| {
+        let old_list = unsafe { (old as *const Mutex<OperationList>).as_ref().unwrap() };
+        let new_list = unsafe { (new as *const Mutex<OperationList>).as_ref().unwrap() };
+        let patch = {
+            let old_list = old_list.lock().unwrap();
+            if old == new {
+                Patch::diff(&old_list, &old_list)
+            } else {
+                Patch::diff(&old_list, &new_list.lock().unwrap())
+            }
+        };
+        let (ptr, len, cap) = match serde_json::to_vec(&patch) {
+            Ok(json) => json.into_raw_parts(),
+            Err(err) => {
+                set_last_error(err);
+                (null_mut(), 0, 0)
+            }
+        };
+        VecInner {
+            ptr: ptr as *const u8,
+            len,
+            cap,
+        }
+    })
+    .unwrap_or(VecInner {
+        ptr: null(),
+        len: 0,
+        cap: 0,
+    })
+}
+
+/// Applies a patch from `simplexp_oplist_diff` to an oplist, given as a JSON string.
+/// Returns false if the patch doesn't apply to the oplist, leaving it unchanged, see
+/// `simplexp_last_error`.
+#[no_mangle]
+pub extern "C" fn simplexp_oplist_apply_patch(
+    oplist: *const (),
+    json: *const u8,
+    length: usize,
+) -> bool {
+    catch_unwind(|| {
+        assert!(!json.is_null());
+        let oplist = unsafe { (oplist as *const Mutex<OperationList>).as_ref().unwrap() };
+        let json = unsafe { std::slice::from_raw_parts(json, length) };
+        let result = serde_json::from_slice::<Patch>(json)
+            .map_err(|err| err.to_string())
+            .and_then(|patch| {
+                let result = oplist.lock().unwrap().apply_patch(&patch);
+                result.map_err(|err| err.to_string())
+            });
+        match result {
+            Ok(()) => true,
+            Err(err) => {
+                set_last_error(err);
+                false
+            }
+        }
+    })
+    .unwrap_or(false)
+}
+
+/// Converts a node of an arena into an optimized expression.
+/// Returns null if the node isn't in the arena, see `simplexp_last_error`.
+#[no_mangle]
+pub extern "C" fn simplexp_arena_to_expr(arena: *const (), id: u32) -> *const ExprPart {
+    catch_unwind(|| {
+        let options = optimizer_options();
+        with_arena(arena, |arena| {
+            if !check_node(arena, id as usize) {
+                return null();
+            }
+            Arc::into_raw(arena.to_optimized_expr(NodeId(id), &options))
+        })
+    })
+    .unwrap_or(null())
+}
+
+/// Appends a node of an arena to an operation list, returning its id in the oplist.
+/// The node is added as it is, without optimizing it.
+/// Returns `usize::MAX` if the node isn't in the arena, see `simplexp_last_error`.
+#[no_mangle]
+pub extern "C" fn simplexp_oplist_append_arena(
+    oplist: *const (),
+    arena: *const (),
+    id: u32,
+) -> usize {
+    catch_unwind(|| {
+        let oplist = unsafe { (oplist as *const Mutex<OperationList>).as_ref().unwrap() };
+        with_arena(arena, |arena| {
+            if !check_node(arena, id as usize) {
+                return usize::MAX;
+            }
+            oplist.lock().unwrap().add_from_arena(arena, NodeId(id))
+        })
+    })
+    .unwrap_or(usize::MAX)
+}
+
+/// Frees a string returned by any of the `simplexp_*` functions, e.g. `simplexp_expr_format`.
 #[no_mangle]
 pub extern "C" fn simplexp_str_free(inner: VecInner) {
     let _ = catch_unwind(|| {
@@ -206,7 +1120,17 @@ pub extern "C" fn simplexp_expr_clone(expr: *const ExprPart) -> *const ExprPart
     .unwrap_or(null())
 }
 
-/// Frees an oplist allocated by the `simplexp_oplist_new` function.
+/// Frees an arena allocated by the `simplexp_arena_new` function.
+#[no_mangle]
+pub extern "C" fn simplexp_arena_free(arena: *const ()) {
+    let _ = catch_unwind(|| {
+        assert!(!arena.is_null());
+        let _ = unsafe { Arc::from_raw(arena as *const Mutex<Arena>) };
+    });
+}
+
+/// Frees an oplist allocated by the `simplexp_oplist_new`, `simplexp_oplist_deserialize` or
+/// `simplexp_oplist_extract` functions.
 #[no_mangle]
 pub extern "C" fn simplexp_oplist_free(oplist: *const ()) {
     let _ = catch_unwind(|| {
@@ -228,3 +1152,50 @@ impl<T> CloneFromPtr<T> for Arc<T> {
         copy
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn last_error() -> String {
+        let vec = simplexp_last_error();
+        let message = unsafe { std::slice::from_raw_parts(vec.ptr, vec.len) };
+        let message = String::from_utf8(message.to_vec()).unwrap();
+        simplexp_str_free(vec);
+        message
+    }
+
+    #[test]
+    fn invalid_arena_ids_are_errors() {
+        let arena = simplexp_arena_new();
+        let oplist = simplexp_oplist_new();
+        let add = ExprOpId::Add as i32;
+        let two = simplexp_arena_int(arena, 2);
+
+        assert_eq!(simplexp_arena_op(arena, add, two, 99, 0, 0, 0), u32::MAX);
+        assert_eq!(last_error(), "node 99 isn't in the arena");
+        assert_eq!(simplexp_arena_op(arena, -1, two, two, 0, 0, 0), u32::MAX);
+        assert_eq!(last_error(), "unknown operation id -1");
+        assert!(simplexp_arena_to_expr(arena, 99).is_null());
+        assert_eq!(last_error(), "node 99 isn't in the arena");
+        assert_eq!(simplexp_oplist_append_arena(oplist, arena, 99), usize::MAX);
+        assert_eq!(last_error(), "node 99 isn't in the arena");
+        let invalid_utf8 = [0xff];
+        assert_eq!(
+            simplexp_arena_str(arena, invalid_utf8.as_ptr(), 1),
+            u32::MAX
+        );
+        assert!(last_error().starts_with("panicked: "));
+
+        // Neither lock is poisoned
+        let sum = simplexp_arena_op(arena, add, two, two, 0, 0, 0);
+        assert_eq!(sum, 1);
+        assert_eq!(simplexp_oplist_append_arena(oplist, arena, sum), 1);
+        assert_eq!(simplexp_oplist_len(oplist), 2);
+        let expr = simplexp_arena_to_expr(arena, sum);
+        assert!(!expr.is_null());
+        simplexp_expr_free(expr);
+        simplexp_oplist_free(oplist);
+        simplexp_arena_free(arena);
+    }
+}
@@ -0,0 +1,81 @@
+use crate::expressions::{ExprOpRef, ExprPartRef};
+use crate::operation_list::OperationList;
+
+const FNV_OFFSET_BASIS: u64 = 0xcbf2_9ce4_8422_2325;
+const FNV_PRIME: u64 = 0x0000_0100_0000_01b3;
+
+/// 64-bit FNV-1a.
+fn fnv1a(bytes: &[u8]) -> u64 {
+    bytes.iter().fold(FNV_OFFSET_BASIS, |hash, &byte| {
+        (hash ^ byte as u64).wrapping_mul(FNV_PRIME)
+    })
+}
+
+fn push_str(bytes: &mut Vec<u8>, value: &str) {
+    bytes.extend_from_slice(&(value.len() as u64).to_le_bytes());
+    bytes.extend_from_slice(value.as_bytes());
+}
+
+/// The content hash of a node, given the content hashes of its operands.
+///
+/// The hash is the 64-bit FNV-1a of the node's bytes, where numbers are little-endian and strings
+/// are their UTF-8 length as a `u64` followed by their UTF-8 bytes:
+/// - an int literal is `0` and the `i64`
+/// - a float literal is `1` and the bits of the `f64`
+/// - a string literal is `2` and the string
+/// - a rational literal is `3`, the numerator and the denominator as `i64`s, reduced with a
+///   positive denominator
+/// - an operation is `4` and its `type` in JSON as a string, e.g. `measureTextX`, then the name of
+///   a variable as a string, or the content hashes of the operands in field order as `u64`s
+pub fn content_hash(node: &ExprPartRef, child_hash: impl Fn(usize) -> u64) -> u64 {
+    let mut bytes = Vec::new();
+    match node {
+        ExprPartRef::IntLiteral(v) => {
+            bytes.push(0);
+            bytes.extend_from_slice(&v.to_le_bytes());
+        }
+        ExprPartRef::FloatLiteral(v) => {
+            bytes.push(1);
+            bytes.extend_from_slice(&v.0.to_bits().to_le_bytes());
+        }
+        ExprPartRef::StringLiteral(v) => {
+            bytes.push(2);
+            push_str(&mut bytes, v);
+        }
+        ExprPartRef::RationalLiteral(v) => {
+            bytes.push(3);
+            bytes.extend_from_slice(&v.num().to_le_bytes());
+            bytes.extend_from_slice(&v.den().to_le_bytes());
+        }
+        ExprPartRef::Operation(op) => {
+            bytes.push(4);
+            push_str(&mut bytes, &op.type_name());
+            if let ExprOpRef::Var { name } = op {
+                push_str(&mut bytes, name);
+            }
+            for child in op.children() {
+                bytes.extend_from_slice(&child_hash(child).to_le_bytes());
+            }
+        }
+    }
+    fnv1a(&bytes)
+}
+
+impl OperationList {
+    /// The content hash of every entry, see `content_hash`. Unlike ids, content hashes only depend
+    /// on the expression of the entry, so they are the same across runs.
+    pub fn content_hashes(&self) -> Vec<u64> {
+        let mut hashes: Vec<u64> = Vec::with_capacity(self.ops.len());
+        for op in &self.ops {
+            let hash = content_hash(op, |child| hashes[child]);
+            hashes.push(hash);
+        }
+        hashes
+    }
+
+    /// The id of the first entry with the content hash. This hashes every entry, so use
+    /// `content_hashes` for many lookups.
+    pub fn find_content_hash(&self, hash: u64) -> Option<usize> {
+        self.content_hashes().into_iter().position(|h| h == hash)
+    }
+}
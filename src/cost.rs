@@ -0,0 +1,25 @@
+use crate::expressions::{ExprOp, ExprPart};
+
+/// The rough relative cost of evaluating an operation in the renderer, excluding its operands.
+pub fn op_cost(op: &ExprOp) -> u32 {
+    match op {
+        ExprOp::Var { .. } | ExprOp::Inf | ExprOp::NegInf => 0,
+        ExprOp::Div { .. } | ExprOp::Fdiv { .. } | ExprOp::Mod { .. } => 2,
+        ExprOp::Pow { .. } | ExprOp::ToStr { .. } | ExprOp::Format { .. } => 4,
+        ExprOp::Concat { .. } | ExprOp::Substr { .. } => 2,
+        ExprOp::MeasureTextX { .. } | ExprOp::MeasureTextY { .. } => 16,
+        _ => 1,
+    }
+}
+
+/// The cost of evaluating the whole expression.
+/// Shared subexpressions are counted once, since they are deduplicated in an oplist.
+pub fn cost(expr: &ExprPart) -> u32 {
+    expr.post_order()
+        .into_iter()
+        .map(|node| match node {
+            ExprPart::Operation(op) => op_cost(op),
+            _ => 0,
+        })
+        .sum()
+}
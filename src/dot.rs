@@ -0,0 +1,138 @@
+use crate::expressions::{ExprPart, ExprPartRef};
+use crate::interner;
+use crate::operation_list::OperationList;
+use std::collections::{HashMap, HashSet};
+use std::fmt::Write;
+use std::sync::Arc;
+
+/// How to draw an expression or an oplist as a Graphviz graph.
+#[derive(Debug, Clone, Copy)]
+pub struct DotOptions<'a, T> {
+    /// Draws the roots in bold: the expression itself, or the named outputs of an oplist and the
+    /// entries no other entry uses.
+    pub highlight_roots: bool,
+    /// Fills the nodes that `changed_since` doesn't have, e.g. to show what optimizing it changed.
+    pub changed_since: Option<&'a T>,
+}
+
+impl<T> Default for DotOptions<'_, T> {
+    fn default() -> Self {
+        DotOptions {
+            highlight_roots: false,
+            changed_since: None,
+        }
+    }
+}
+
+/// Draws an expression in the Graphviz DOT language, shared nodes are drawn once.
+pub fn expr_to_dot(expr: &Arc<ExprPart>, options: &DotOptions<Arc<ExprPart>>) -> String {
+    // Interned expressions share equal nodes, so the nodes of both can be compared by pointer
+    let expr = interner::intern(expr.clone());
+    let before = options
+        .changed_since
+        .map(|before| interner::intern(before.clone()));
+    let before_nodes: Option<HashSet<*const ExprPart>> = before.as_ref().map(|before| {
+        before
+            .post_order()
+            .into_iter()
+            .map(|node| node as *const _)
+            .collect()
+    });
+
+    let mut ids: HashMap<*const ExprPart, usize> = HashMap::new();
+    let mut nodes = Vec::new();
+    let mut changed = Vec::new();
+    for node in expr.post_order() {
+        nodes.push(node.to_expr_part_ref(|child| ids[&Arc::as_ptr(child)]));
+        changed.push(
+            before_nodes
+                .as_ref()
+                .is_some_and(|before| !before.contains(&(node as *const _))),
+        );
+        ids.insert(node, ids.len());
+    }
+
+    let mut roots = vec![false; nodes.len()];
+    roots[nodes.len() - 1] = options.highlight_roots;
+    write_dot(&nodes, &roots, &HashMap::new(), &changed)
+}
+
+/// Draws an oplist in the Graphviz DOT language, labeling entries with their named outputs.
+pub fn oplist_to_dot(oplist: &OperationList, options: &DotOptions<OperationList>) -> String {
+    // Entries of the new oplist are only inserted into a copy of the old one if they are new
+    let changed = match options.changed_since {
+        Some(before) => {
+            let mut combined = before.clone();
+            let ids = combined.merge(oplist);
+            ids.into_iter().map(|id| id >= before.ops.len()).collect()
+        }
+        None => vec![false; oplist.ops.len()],
+    };
+
+    let mut names: HashMap<usize, Vec<&str>> = HashMap::new();
+    for (name, &id) in &oplist.outputs {
+        names.entry(id).or_default().push(name);
+    }
+
+    let mut roots = vec![options.highlight_roots; oplist.ops.len()];
+    if options.highlight_roots {
+        for op in &oplist.ops {
+            for child in op.children() {
+                if let Some(root) = roots.get_mut(child) {
+                    *root = names.contains_key(&child);
+                }
+            }
+        }
+    }
+    write_dot(&oplist.ops, &roots, &names, &changed)
+}
+
+fn write_dot(
+    nodes: &[ExprPartRef],
+    roots: &[bool],
+    names: &HashMap<usize, Vec<&str>>,
+    changed: &[bool],
+) -> String {
+    let mut dot = String::from("digraph {\n    node [shape=box, fontname=monospace];\n");
+    for (id, node) in nodes.iter().enumerate() {
+        let mut label = match node {
+            ExprPartRef::Operation(op) => op.format_fields(),
+            _ => format!("{:?}", node.to_expr_part(|_| unreachable!())),
+        };
+        if let Some(names) = names.get(&id) {
+            label = format!("{}\n{}", names.join("\n"), label);
+        }
+
+        let mut styles = Vec::new();
+        if roots[id] {
+            styles.push("bold");
+        }
+        if changed[id] {
+            styles.push("filled");
+        }
+        write!(dot, "    n{} [label=\"{}\"", id, escape(&label)).unwrap();
+        if !styles.is_empty() {
+            write!(dot, ", style=\"{}\"", styles.join(",")).unwrap();
+        }
+        if changed[id] {
+            dot.push_str(", fillcolor=lightyellow");
+        }
+        dot.push_str("];\n");
+
+        if let ExprPartRef::Operation(op) = node {
+            for (field, child) in op.field_names().iter().zip(op.children()) {
+                writeln!(dot, "    n{} -> n{} [label=\"{}\"];", id, child, field).unwrap();
+            }
+        }
+    }
+    dot.push_str("}\n");
+    dot
+}
+
+/// Escapes a label for a quoted DOT string, where `\n` is a line break.
+fn escape(label: &str) -> String {
+    label
+        .replace('\\', "\\\\")
+        .replace('"', "\\\"")
+        .replace('\n', "\\n")
+}
@@ -0,0 +1,697 @@
+use crate::expressions::{self, ExprOp, ExprPart, NanError};
+use crate::operation_list::OperationList;
+use serde::Serialize;
+use std::cmp::Ordering;
+use std::collections::HashMap;
+use std::convert::TryFrom;
+use std::fmt::{Display, Formatter};
+use std::panic::{catch_unwind, resume_unwind, AssertUnwindSafe};
+use std::sync::atomic::{self, AtomicBool, AtomicUsize};
+use std::sync::{Arc, Barrier, Mutex, OnceLock};
+
+/// The result of evaluating an expression.
+///
+/// Comparisons evaluate to `Int(0)` or `Int(1)`.
+#[derive(Serialize, Debug, Clone, PartialEq)]
+#[serde(untagged)]
+pub enum Value {
+    Int(i64),
+    Float(f64),
+    String(String),
+}
+
+impl Value {
+    fn as_f64(&self) -> Result<f64, EvalError> {
+        match self {
+            Value::Int(v) => Ok(*v as f64),
+            Value::Float(v) => Ok(*v),
+            Value::String(_) => Err(EvalError::TypeMismatch("operands must be numeric")),
+        }
+    }
+
+    fn as_int(&self) -> Result<i64, EvalError> {
+        match self {
+            Value::Int(v) => Ok(*v),
+            _ => Err(EvalError::TypeMismatch("operands must be ints")),
+        }
+    }
+
+    fn into_string(self) -> Result<String, EvalError> {
+        match self {
+            Value::String(v) => Ok(v),
+            _ => Err(EvalError::TypeMismatch("operands must be strings")),
+        }
+    }
+
+    fn is_truthy(&self) -> Result<bool, EvalError> {
+        match self {
+            Value::Int(v) => Ok(*v != 0),
+            Value::Float(v) => Ok(*v != 0.0),
+            Value::String(_) => Err(EvalError::TypeMismatch("condition must be numeric")),
+        }
+    }
+}
+
+impl From<bool> for Value {
+    fn from(value: bool) -> Self {
+        Value::Int(value as i64)
+    }
+}
+
+impl From<Value> for ExprPart {
+    fn from(value: Value) -> Self {
+        match value {
+            Value::Int(v) => ExprPart::IntLiteral(v),
+            Value::Float(v) if v == f64::INFINITY => ExprPart::Operation(ExprOp::Inf),
+            Value::Float(v) if v == f64::NEG_INFINITY => ExprPart::Operation(ExprOp::NegInf),
+            Value::Float(v) => ExprPart::FloatLiteral(v),
+            Value::String(v) => ExprPart::StringLiteral(v),
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum EvalError {
+    UnboundVariable(String),
+    TypeMismatch(&'static str),
+    DivisionByZero,
+    Overflow,
+    NoTextMeasurer,
+    NotANumber(NanError),
+    /// An operand is not a literal, only returned while folding constants in the optimizer.
+    NotConstant,
+}
+
+impl Display for EvalError {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        match self {
+            EvalError::UnboundVariable(name) => write!(f, "unbound variable: {name}"),
+            EvalError::TypeMismatch(message) => write!(f, "type mismatch: {message}"),
+            EvalError::DivisionByZero => write!(f, "division by zero"),
+            EvalError::Overflow => write!(f, "integer overflow"),
+            EvalError::NoTextMeasurer => write!(f, "no text measurer was provided"),
+            EvalError::NotANumber(err) => write!(f, "{err}"),
+            EvalError::NotConstant => write!(f, "operand is not a constant"),
+        }
+    }
+}
+
+impl std::error::Error for EvalError {}
+
+type TextMeasurer = dyn Fn(&str, f64) -> (f64, f64) + Send + Sync;
+
+/// Variable bindings and callbacks used while evaluating an expression.
+#[derive(Default)]
+pub struct Context {
+    vars: HashMap<String, Value>,
+    measure_text: Option<Box<TextMeasurer>>,
+}
+
+impl Context {
+    pub fn new() -> Self {
+        Context::default()
+    }
+
+    pub fn with_var(mut self, name: &str, value: Value) -> Self {
+        self.vars.insert(name.to_string(), value);
+        self
+    }
+
+    /// Sets the callback used by `MeasureTextX` and `MeasureTextY`.
+    /// It receives the text and font size, and returns the width and height.
+    pub fn with_text_measurer(
+        mut self,
+        measure_text: impl Fn(&str, f64) -> (f64, f64) + Send + Sync + 'static,
+    ) -> Self {
+        self.measure_text = Some(Box::new(measure_text));
+        self
+    }
+}
+
+/// Evaluates an expression.
+///
+/// Int operations are checked and fail on overflow, everything involving a float follows IEEE-754,
+/// except that producing NaN is an error.
+/// `Div` always produces a float, `Fdiv` and `Mod` floor like Python does.
+pub fn evaluate(expr: &ExprPart, ctx: &Context) -> Result<Value, EvalError> {
+    // Operands are evaluated before the operations using them, with an explicit stack so that deep
+    // expressions can't overflow the stack. Shared operands are evaluated once.
+    let mut values: HashMap<*const ExprPart, Value> = HashMap::new();
+    let mut stack = vec![expr];
+    while let Some(&node) = stack.last() {
+        if values.contains_key(&(node as *const ExprPart)) {
+            stack.pop();
+            continue;
+        }
+        let pending: Vec<&ExprPart> = operands(node, &values)?
+            .into_iter()
+            .filter(|operand| !values.contains_key(&(*operand as *const ExprPart)))
+            .collect();
+        if !pending.is_empty() {
+            stack.extend(pending.into_iter().rev());
+            continue;
+        }
+
+        stack.pop();
+        let value = eval_node(node, ctx, |operand| {
+            Ok(values[&(operand as *const ExprPart)].clone())
+        })?;
+        values.insert(node, value);
+    }
+    Ok(values.remove(&(expr as *const ExprPart)).unwrap())
+}
+
+/// Evaluates every entry of an oplist, returning the value of every entry, or why it failed.
+///
+/// Entries are evaluated level by level, see `OperationList::levels`. With more than one thread,
+/// the entries of each level are spread across the threads, so that e.g. expensive `MeasureTextX`
+/// entries run concurrently. Both branches of an `If` are evaluated, but like with `evaluate`, an
+/// entry only fails if an operand it uses fails.
+pub fn evaluate_oplist(
+    oplist: &OperationList,
+    ctx: &Context,
+    threads: usize,
+) -> Vec<Result<Value, EvalError>> {
+    let exprs = expressions::build_all(&oplist.ops, |expr| expr);
+    // Keyed by address, since pointers can't be shared between threads
+    let ids: HashMap<usize, usize> = exprs
+        .iter()
+        .enumerate()
+        .map(|(id, expr)| (Arc::as_ptr(expr) as usize, id))
+        .collect();
+    let results: Vec<OnceLock<Result<Value, EvalError>>> =
+        exprs.iter().map(|_| OnceLock::new()).collect();
+    let eval_entry = |id: usize| {
+        let value = eval_node(&exprs[id], ctx, |operand| {
+            results[ids[&(operand as *const ExprPart as usize)]]
+                .get()
+                .unwrap()
+                .clone()
+        });
+        let _ = results[id].set(value);
+    };
+
+    let levels = oplist.levels();
+    if threads <= 1 {
+        levels.iter().flatten().for_each(|&id| eval_entry(id));
+    } else {
+        // Every thread takes the next entry of the level until there are none left, and then waits
+        // for the others to finish the level. Panics are resumed once every thread is done, so
+        // that the others don't wait for a thread that panicked, and no more entries are evaluated.
+        let next: Vec<AtomicUsize> = levels.iter().map(|_| AtomicUsize::new(0)).collect();
+        let barrier = Barrier::new(threads);
+        let panic = Mutex::new(None);
+        let panicked = AtomicBool::new(false);
+        std::thread::scope(|scope| {
+            for _ in 0..threads {
+                scope.spawn(|| {
+                    for (level, next) in levels.iter().zip(&next) {
+                        while let Some(&id) =
+                            level.get(next.fetch_add(1, atomic::Ordering::Relaxed))
+                        {
+                            if panicked.load(atomic::Ordering::Relaxed) {
+                                continue;
+                            }
+                            if let Err(payload) = catch_unwind(AssertUnwindSafe(|| eval_entry(id)))
+                            {
+                                panicked.store(true, atomic::Ordering::Relaxed);
+                                *panic.lock().unwrap() = Some(payload);
+                            }
+                        }
+                        barrier.wait();
+                    }
+                });
+            }
+        });
+        if let Some(payload) = panic.into_inner().unwrap() {
+            resume_unwind(payload);
+        }
+    }
+    results
+        .into_iter()
+        .map(|result| result.into_inner().unwrap())
+        .collect()
+}
+
+/// Evaluates a single node, using `eval_child` to evaluate its operands.
+fn eval_node(
+    node: &ExprPart,
+    ctx: &Context,
+    eval_child: impl FnMut(&ExprPart) -> Result<Value, EvalError>,
+) -> Result<Value, EvalError> {
+    match node {
+        ExprPart::IntLiteral(v) => Ok(Value::Int(*v)),
+        ExprPart::FloatLiteral(v) if v.is_nan() => Err(EvalError::NotANumber(NanError)),
+        ExprPart::FloatLiteral(v) => Ok(Value::Float(*v)),
+        ExprPart::StringLiteral(v) => Ok(Value::String(v.clone())),
+        ExprPart::RationalLiteral(v) => Ok(Value::Float(v.to_f64())),
+        ExprPart::Operation(op) => eval_op(op, ctx, eval_child),
+    }
+}
+
+/// The operands `eval_op` evaluates for `expr`, given the values evaluated so far.
+/// `If` only evaluates the branch its condition selects, so its operands depend on the condition.
+fn operands<'a>(
+    expr: &'a ExprPart,
+    values: &HashMap<*const ExprPart, Value>,
+) -> Result<Vec<&'a ExprPart>, EvalError> {
+    let op = match expr {
+        ExprPart::Operation(op) => op,
+        _ => return Ok(vec![]),
+    };
+    Ok(match op {
+        ExprOp::If { cond, t, f } => match values.get(&Arc::as_ptr(cond)) {
+            Some(cond_value) if cond_value.is_truthy()? => vec![&**cond, &**t],
+            Some(_) => vec![&**cond, &**f],
+            None => vec![&**cond],
+        },
+        // Nested formats are evaluated as a whole, see `eval_op`.
+        // Arguments without a placeholder left are never evaluated.
+        ExprOp::Format { template, a } => {
+            let mut args = vec![&**a];
+            let mut template = template;
+            while let ExprPart::Operation(ExprOp::Format { template: inner, a }) = &**template {
+                args.push(a);
+                template = inner;
+            }
+            let placeholders = match values.get(&Arc::as_ptr(template)) {
+                Some(Value::String(template)) => template.matches("{}").count(),
+                _ => 0,
+            };
+            std::iter::once(&**template)
+                .chain(args.into_iter().rev().take(placeholders))
+                .collect()
+        }
+        _ => op.children().into_iter().map(|child| &**child).collect(),
+    })
+}
+
+/// Evaluates a single operation, using `eval_child` to evaluate its operands.
+/// This is shared with the optimizer, so that constant folding matches evaluation exactly.
+/// A NaN result is an error, which also keeps the optimizer from folding it.
+pub(crate) fn eval_op(
+    op: &ExprOp,
+    ctx: &Context,
+    eval_child: impl FnMut(&ExprPart) -> Result<Value, EvalError>,
+) -> Result<Value, EvalError> {
+    match eval_op_unchecked(op, ctx, eval_child)? {
+        Value::Float(v) if v.is_nan() => Err(EvalError::NotANumber(NanError)),
+        value => Ok(value),
+    }
+}
+
+fn eval_op_unchecked(
+    op: &ExprOp,
+    ctx: &Context,
+    mut eval_child: impl FnMut(&ExprPart) -> Result<Value, EvalError>,
+) -> Result<Value, EvalError> {
+    match op {
+        ExprOp::Var { name } => ctx
+            .vars
+            .get(name)
+            .cloned()
+            .ok_or_else(|| EvalError::UnboundVariable(name.clone())),
+        ExprOp::Inf => Ok(Value::Float(f64::INFINITY)),
+        ExprOp::NegInf => Ok(Value::Float(f64::NEG_INFINITY)),
+        ExprOp::Add { a, b } => arith(
+            eval_child(a)?,
+            eval_child(b)?,
+            |a, b| a.checked_add(b).ok_or(EvalError::Overflow),
+            |a, b| a + b,
+        ),
+        ExprOp::Mul { a, b } => arith(
+            eval_child(a)?,
+            eval_child(b)?,
+            |a, b| a.checked_mul(b).ok_or(EvalError::Overflow),
+            |a, b| a * b,
+        ),
+        ExprOp::Div { a, b } => {
+            let (a, b) = (eval_child(a)?, eval_child(b)?);
+            Ok(Value::Float(a.as_f64()? / b.as_f64()?))
+        }
+        ExprOp::Fdiv { a, b } => arith(eval_child(a)?, eval_child(b)?, floor_div, |a, b| {
+            (a / b).floor()
+        }),
+        ExprOp::Mod { a, b } => arith(eval_child(a)?, eval_child(b)?, floor_mod, |a, b| {
+            let rem = a % b;
+            if rem != 0.0 && (rem < 0.0) != (b < 0.0) {
+                rem + b
+            } else {
+                rem
+            }
+        }),
+        ExprOp::Pow { a, b } => match (eval_child(a)?, eval_child(b)?) {
+            (Value::Int(a), Value::Int(b)) if b >= 0 => u32::try_from(b)
+                .ok()
+                .and_then(|b| a.checked_pow(b))
+                .map(Value::Int)
+                .ok_or(EvalError::Overflow),
+            (a, b) => Ok(Value::Float(a.as_f64()?.powf(b.as_f64()?))),
+        },
+        ExprOp::Eq { a, b } => Ok(Value::from(equals(&eval_child(a)?, &eval_child(b)?)?)),
+        ExprOp::Neq { a, b } => Ok(Value::from(!equals(&eval_child(a)?, &eval_child(b)?)?)),
+        ExprOp::Lt { a, b } => Ok(Value::from(matches!(
+            compare(&eval_child(a)?, &eval_child(b)?)?,
+            Some(Ordering::Less)
+        ))),
+        ExprOp::Lte { a, b } => Ok(Value::from(matches!(
+            compare(&eval_child(a)?, &eval_child(b)?)?,
+            Some(Ordering::Less | Ordering::Equal)
+        ))),
+        ExprOp::Gt { a, b } => Ok(Value::from(matches!(
+            compare(&eval_child(a)?, &eval_child(b)?)?,
+            Some(Ordering::Greater)
+        ))),
+        ExprOp::Gte { a, b } => Ok(Value::from(matches!(
+            compare(&eval_child(a)?, &eval_child(b)?)?,
+            Some(Ordering::Greater | Ordering::Equal)
+        ))),
+        ExprOp::BAnd { a, b } => Ok(Value::Int(
+            eval_child(a)?.as_int()? & eval_child(b)?.as_int()?,
+        )),
+        ExprOp::BOr { a, b } => Ok(Value::Int(
+            eval_child(a)?.as_int()? | eval_child(b)?.as_int()?,
+        )),
+        ExprOp::Neg { a } => match eval_child(a)? {
+            Value::Int(v) => v.checked_neg().map(Value::Int).ok_or(EvalError::Overflow),
+            v => Ok(Value::Float(-v.as_f64()?)),
+        },
+        ExprOp::BInvert { a } => Ok(Value::Int(!eval_child(a)?.as_int()?)),
+        ExprOp::Min { a, b } => arith(
+            eval_child(a)?,
+            eval_child(b)?,
+            |a, b| Ok(a.min(b)),
+            f64::min,
+        ),
+        ExprOp::Max { a, b } => arith(
+            eval_child(a)?,
+            eval_child(b)?,
+            |a, b| Ok(a.max(b)),
+            f64::max,
+        ),
+        ExprOp::Abs { a } => match eval_child(a)? {
+            Value::Int(v) => v.checked_abs().map(Value::Int).ok_or(EvalError::Overflow),
+            v => Ok(Value::Float(v.as_f64()?.abs())),
+        },
+        ExprOp::ToStr { a } => Ok(Value::String(to_str(eval_child(a)?))),
+        ExprOp::MeasureTextX { text, font_size } => {
+            let (width, _) = measure_text(ctx, eval_child(text)?, eval_child(font_size)?)?;
+            Ok(Value::Float(width))
+        }
+        ExprOp::MeasureTextY { text, font_size } => {
+            let (_, height) = measure_text(ctx, eval_child(text)?, eval_child(font_size)?)?;
+            Ok(Value::Float(height))
+        }
+        ExprOp::If { cond, t, f } => {
+            if eval_child(cond)?.is_truthy()? {
+                eval_child(t)
+            } else {
+                eval_child(f)
+            }
+        }
+        ExprOp::Concat { a, b } => {
+            let (a, b) = (eval_child(a)?.into_string()?, eval_child(b)?.into_string()?);
+            Ok(Value::String(a + &b))
+        }
+        ExprOp::StrLen { a } => Ok(Value::Int(
+            eval_child(a)?.into_string()?.chars().count() as i64
+        )),
+        ExprOp::Substr { text, start, end } => {
+            let text: Vec<char> = eval_child(text)?.into_string()?.chars().collect();
+            let (start, end) = (eval_child(start)?.as_int()?, eval_child(end)?.as_int()?);
+            let (start, end) = (slice_index(start, text.len()), slice_index(end, text.len()));
+            Ok(Value::String(text[start..end.max(start)].iter().collect()))
+        }
+        ExprOp::Format { template, a } => {
+            // Nested formats fill the placeholders of the innermost template in order,
+            // so the inserted text is never searched for placeholders
+            let mut args = vec![a];
+            let mut template = template;
+            while let ExprPart::Operation(ExprOp::Format { template: inner, a }) = &**template {
+                args.push(a);
+                template = inner;
+            }
+
+            let template = eval_child(template)?.into_string()?;
+            let mut rest = template.as_str();
+            let mut formatted = String::new();
+            for arg in args.into_iter().rev() {
+                let idx = match rest.find("{}") {
+                    Some(idx) => idx,
+                    None => break,
+                };
+                formatted.push_str(&rest[..idx]);
+                formatted.push_str(&to_str(eval_child(arg)?));
+                rest = &rest[idx + 2..];
+            }
+            formatted.push_str(rest);
+            Ok(Value::String(formatted))
+        }
+    }
+}
+
+/// Formats a value for `toStr` and `format`.
+///
+/// Ints are formatted in decimal. Floats use the shortest decimal representation that parses back
+/// to the same value, without an exponent, and always with a fractional part (`1.0`, `0.1`, `-0.0`).
+/// Infinities are `inf` and `-inf`. Strings are left as they are.
+pub fn to_str(value: Value) -> String {
+    match value {
+        Value::Int(v) => v.to_string(),
+        Value::Float(v) if v.is_finite() && v.fract() == 0.0 => format!("{v:.1}"),
+        Value::Float(v) => v.to_string(),
+        Value::String(v) => v,
+    }
+}
+
+/// Resolves a Python-style slice index, negative indices count from the end.
+fn slice_index(idx: i64, len: usize) -> usize {
+    if idx < 0 {
+        len.saturating_sub(idx.unsigned_abs() as usize)
+    } else {
+        (idx as usize).min(len)
+    }
+}
+
+fn arith(
+    a: Value,
+    b: Value,
+    int_op: impl FnOnce(i64, i64) -> Result<i64, EvalError>,
+    float_op: impl FnOnce(f64, f64) -> f64,
+) -> Result<Value, EvalError> {
+    match (a, b) {
+        (Value::Int(a), Value::Int(b)) => int_op(a, b).map(Value::Int),
+        (a, b) => Ok(Value::Float(float_op(a.as_f64()?, b.as_f64()?))),
+    }
+}
+
+fn floor_div(a: i64, b: i64) -> Result<i64, EvalError> {
+    if b == 0 {
+        return Err(EvalError::DivisionByZero);
+    }
+    let quot = a.checked_div(b).ok_or(EvalError::Overflow)?;
+    if a % b != 0 && (a < 0) != (b < 0) {
+        Ok(quot - 1)
+    } else {
+        Ok(quot)
+    }
+}
+
+fn floor_mod(a: i64, b: i64) -> Result<i64, EvalError> {
+    if b == 0 {
+        return Err(EvalError::DivisionByZero);
+    }
+    let rem = a.checked_rem(b).ok_or(EvalError::Overflow)?;
+    if rem != 0 && (rem < 0) != (b < 0) {
+        Ok(rem + b)
+    } else {
+        Ok(rem)
+    }
+}
+
+fn equals(a: &Value, b: &Value) -> Result<bool, EvalError> {
+    Ok(match (a, b) {
+        (Value::Int(a), Value::Int(b)) => a == b,
+        (Value::String(a), Value::String(b)) => a == b,
+        (Value::String(_), _) | (_, Value::String(_)) => false,
+        (a, b) => a.as_f64()? == b.as_f64()?,
+    })
+}
+
+fn compare(a: &Value, b: &Value) -> Result<Option<Ordering>, EvalError> {
+    match (a, b) {
+        (Value::Int(a), Value::Int(b)) => Ok(a.partial_cmp(b)),
+        (Value::String(a), Value::String(b)) => Ok(a.partial_cmp(b)),
+        (Value::String(_), _) | (_, Value::String(_)) => Err(EvalError::TypeMismatch(
+            "cannot compare a string with a number",
+        )),
+        (a, b) => Ok(a.as_f64()?.partial_cmp(&b.as_f64()?)),
+    }
+}
+
+fn measure_text(ctx: &Context, text: Value, font_size: Value) -> Result<(f64, f64), EvalError> {
+    let text = match text {
+        Value::String(text) => text,
+        _ => return Err(EvalError::TypeMismatch("text must be a string")),
+    };
+    let measure_text = ctx.measure_text.as_ref().ok_or(EvalError::NoTextMeasurer)?;
+    Ok(measure_text(&text, font_size.as_f64()?))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::expressions::{ExprOpRef, ExprPartRef};
+    use eq_float::F64;
+
+    /// A xorshift generator, so that failures are reproducible.
+    struct Rng(u64);
+
+    impl Rng {
+        fn below(&mut self, n: usize) -> usize {
+            self.0 ^= self.0 << 13;
+            self.0 ^= self.0 >> 7;
+            self.0 ^= self.0 << 17;
+            (self.0 % n as u64) as usize
+        }
+    }
+
+    fn random_oplist(rng: &mut Rng, len: usize) -> OperationList {
+        let mut ops = Vec::with_capacity(len);
+        for id in 0..len {
+            let kind = if id == 0 { 0 } else { rng.below(10) };
+            let mut operand = || rng.below(id.max(1));
+            let op = match kind {
+                0 => ExprPartRef::IntLiteral(operand() as i64 % 5 - 2),
+                1 => ExprPartRef::FloatLiteral(F64(operand() as f64 % 5.0 - 2.0)),
+                2 => ExprPartRef::Operation(ExprOpRef::Var {
+                    name: ["x", "y"][operand() % 2].to_string(),
+                }),
+                3 => ExprPartRef::Operation(ExprOpRef::Add {
+                    a: operand(),
+                    b: operand(),
+                }),
+                4 => ExprPartRef::Operation(ExprOpRef::Mul {
+                    a: operand(),
+                    b: operand(),
+                }),
+                5 => ExprPartRef::Operation(ExprOpRef::Fdiv {
+                    a: operand(),
+                    b: operand(),
+                }),
+                6 => ExprPartRef::Operation(ExprOpRef::Div {
+                    a: operand(),
+                    b: operand(),
+                }),
+                7 => ExprPartRef::Operation(ExprOpRef::Min {
+                    a: operand(),
+                    b: operand(),
+                }),
+                8 => ExprPartRef::Operation(ExprOpRef::Lt {
+                    a: operand(),
+                    b: operand(),
+                }),
+                _ => ExprPartRef::Operation(ExprOpRef::If {
+                    cond: operand(),
+                    t: operand(),
+                    f: operand(),
+                }),
+            };
+            ops.push(op);
+        }
+        OperationList::from_ops(ops).unwrap()
+    }
+
+    fn context() -> Context {
+        Context::new()
+            .with_var("x", Value::Int(3))
+            .with_var("y", Value::Float(-0.0))
+    }
+
+    #[test]
+    fn levels_come_after_operands() {
+        let mut rng = Rng(0x2545_f491_4f6c_dd1d);
+        for _ in 0..100 {
+            let oplist = random_oplist(&mut rng, 200);
+            let mut entry_levels = vec![None; oplist.ops.len()];
+            for (level, ids) in oplist.levels().iter().enumerate() {
+                for &id in ids {
+                    assert_eq!(entry_levels[id], None, "entry {} is in two levels", id);
+                    entry_levels[id] = Some(level);
+                }
+            }
+            for (id, op) in oplist.ops.iter().enumerate() {
+                let level = entry_levels[id].unwrap();
+                let operand_levels = op.children().into_iter().map(|c| entry_levels[c].unwrap());
+                assert_eq!(level, operand_levels.max().map_or(0, |max| max + 1));
+            }
+        }
+    }
+
+    #[test]
+    fn threads_match_sequential_evaluation() {
+        let mut rng = Rng(0x9e37_79b9_7f4a_7c15);
+        let ctx = context();
+        for _ in 0..100 {
+            let oplist = random_oplist(&mut rng, 200);
+            // Compared by debug representation, so that -0.0 and 0.0 differ
+            let expected: Vec<String> = (0..oplist.ops.len())
+                .map(|id| format!("{:?}", evaluate(&oplist.to_expr(id), &ctx)))
+                .collect();
+            for threads in [1, 4] {
+                let results = evaluate_oplist(&oplist, &ctx, threads);
+                let results: Vec<String> = results.iter().map(|r| format!("{:?}", r)).collect();
+                assert_eq!(results, expected, "with {} threads", threads);
+            }
+        }
+    }
+
+    #[test]
+    fn untaken_branches_dont_fail() {
+        let oplist = OperationList::from_ops(vec![
+            ExprPartRef::IntLiteral(1),
+            ExprPartRef::IntLiteral(0),
+            ExprPartRef::Operation(ExprOpRef::Fdiv { a: 0, b: 1 }),
+            ExprPartRef::Operation(ExprOpRef::If {
+                cond: 0,
+                t: 0,
+                f: 2,
+            }),
+            ExprPartRef::StringLiteral("no placeholders".to_string()),
+            ExprPartRef::Operation(ExprOpRef::Format { template: 4, a: 2 }),
+            ExprPartRef::Operation(ExprOpRef::If {
+                cond: 1,
+                t: 0,
+                f: 2,
+            }),
+        ])
+        .unwrap();
+        for threads in [1, 4] {
+            let results = evaluate_oplist(&oplist, &Context::new(), threads);
+            assert_eq!(results[2], Err(EvalError::DivisionByZero));
+            assert_eq!(results[3], Ok(Value::Int(1)));
+            assert_eq!(results[5], Ok(Value::String("no placeholders".to_string())));
+            assert_eq!(results[6], Err(EvalError::DivisionByZero));
+        }
+    }
+
+    #[test]
+    fn worker_panics_are_resumed() {
+        let mut ops = vec![
+            ExprPartRef::StringLiteral("text".to_string()),
+            ExprPartRef::IntLiteral(12),
+        ];
+        for _ in 0..8 {
+            ops.push(ExprPartRef::Operation(ExprOpRef::MeasureTextX {
+                text: 0,
+                font_size: 1,
+            }));
+        }
+        // Identical entries aren't merged by `from_ops`, so every one of them measures
+        let oplist = OperationList::from_ops(ops).unwrap();
+        let ctx = Context::new().with_text_measurer(|_, _| panic!("measuring failed"));
+        let result = catch_unwind(AssertUnwindSafe(|| evaluate_oplist(&oplist, &ctx, 4)));
+        let payload = result.unwrap_err();
+        assert_eq!(payload.downcast_ref::<&str>(), Some(&"measuring failed"));
+    }
+}
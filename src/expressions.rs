@@ -1,5 +1,12 @@
+use crate::rational::Rational;
 use eq_float::F64;
-use std::fmt::{Debug, Formatter};
+use serde::de::Error as _;
+use serde::ser::Error;
+use serde::{Deserialize, Deserializer, Serializer};
+use serde_json::value::RawValue;
+use std::collections::{BTreeMap, HashSet};
+use std::fmt::{Debug, Display, Formatter};
+use std::hash::{Hash, Hasher};
 use std::sync::Arc;
 
 macro_rules! type_to_ref {
@@ -20,6 +27,25 @@ macro_rules! type_to_realtype {
     };
 }
 
+/// The position of an operand id in `ExprOpRef::from_children`, e.g. `child2` is 1.
+macro_rules! child_index {
+    (child1) => {
+        0
+    };
+    (child2) => {
+        1
+    };
+    (child3) => {
+        2
+    };
+    (child4) => {
+        3
+    };
+    (child5) => {
+        4
+    };
+}
+
 macro_rules! define_ops {
     (
         $($name:ident: {
@@ -44,6 +70,7 @@ macro_rules! define_ops {
             #[serde(rename_all = "camelCase")]
             Var { name: String },
             Inf,
+            NegInf,
             $(
                 #[serde(rename_all = "camelCase")]
                 $name {
@@ -52,12 +79,13 @@ macro_rules! define_ops {
             )*
         }
 
-        #[derive(Serialize, Debug, Clone, Hash, Eq, PartialEq)]
-        #[serde(tag = "type", rename_all = "camelCase")]
+        #[derive(Serialize, Deserialize, Debug, Clone, Hash, Eq, PartialEq)]
+        #[serde(tag = "type", rename_all = "camelCase", deny_unknown_fields)]
         pub enum ExprOpRef {
             #[serde(rename_all = "camelCase")]
             Var { name: String },
             Inf,
+            NegInf,
             $(
                 #[serde(rename_all = "camelCase")]
                 $name {
@@ -67,13 +95,49 @@ macro_rules! define_ops {
         }
 
         impl ExprOp {
-            pub fn to_expr_op_ref(&self, oplist: &mut crate::operation_list::OperationList) -> ExprOpRef {
+            /// The operation with every operand replaced by its id, e.g. in an oplist.
+            pub fn to_expr_op_ref(&self, mut child_id: impl FnMut(&Arc<ExprPart>) -> usize) -> ExprOpRef {
                 match self {
                     ExprOp::Var { name } => ExprOpRef::Var { name: name.clone() },
                     ExprOp::Inf => ExprOpRef::Inf,
+                    ExprOp::NegInf => ExprOpRef::NegInf,
                     $(
                         ExprOp::$name { $($field,)* } => ExprOpRef::$name {
-                            $($field: oplist.add($field),)*
+                            $($field: child_id($field),)*
+                        },
+                    )*
+                }
+            }
+
+            /// The operands of this operation, in field order.
+            pub fn children(&self) -> Vec<&Arc<ExprPart>> {
+                match self {
+                    ExprOp::Var { .. } | ExprOp::Inf | ExprOp::NegInf => vec![],
+                    $(
+                        ExprOp::$name { $($field,)* } => vec![$($field,)*],
+                    )*
+                }
+            }
+
+            pub(crate) fn children_mut(&mut self) -> Vec<&mut Arc<ExprPart>> {
+                match self {
+                    ExprOp::Var { .. } | ExprOp::Inf | ExprOp::NegInf => vec![],
+                    $(
+                        ExprOp::$name { $($field,)* } => vec![$($field,)*],
+                    )*
+                }
+            }
+
+            /// The operation formatted with every operand replaced by `Hole`.
+            fn format_shallow(&self) -> String {
+                match self {
+                    ExprOp::Var { name } => name.clone(),
+                    ExprOp::Inf => "INF".to_string(),
+                    ExprOp::NegInf => "-INF".to_string(),
+                    $(
+                        ExprOp::$name { .. } => {
+                            $(let $field = Hole;)*
+                            format!($format)
                         },
                     )*
                 }
@@ -105,52 +169,576 @@ macro_rules! define_ops {
             }
         }
 
-        impl core::fmt::Debug for ExprOp {
-            fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> std::fmt::Result {
+        impl ExprOpRef {
+            /// The `type` of the operation in JSON, e.g. `measureTextX`.
+            pub fn type_name(&self) -> String {
+                let name = match self {
+                    ExprOpRef::Var { .. } => "Var",
+                    ExprOpRef::Inf => "Inf",
+                    ExprOpRef::NegInf => "NegInf",
+                    $(ExprOpRef::$name { .. } => stringify!($name),)*
+                };
+                lower_camel_case(name)
+            }
+
+            /// The `type` and the field names of every operation, e.g. `"add": ["a", "b"]`, which
+            /// describe the JSON format of operations.
+            pub fn op_set() -> BTreeMap<String, Vec<String>> {
+                let mut op_set = BTreeMap::new();
+                op_set.insert(lower_camel_case("Var"), vec!["name".to_string()]);
+                op_set.insert(lower_camel_case("Inf"), vec![]);
+                op_set.insert(lower_camel_case("NegInf"), vec![]);
+                $(
+                    op_set.insert(
+                        lower_camel_case(stringify!($name)),
+                        vec![$(field_camel_case(stringify!($field))),*],
+                    );
+                )*
+                op_set
+            }
+
+            /// The names of the operand fields of this operation, in field order.
+            pub fn field_names(&self) -> &'static [&'static str] {
+                match self {
+                    ExprOpRef::Var { .. } | ExprOpRef::Inf | ExprOpRef::NegInf => &[],
+                    $(ExprOpRef::$name { .. } => &[$(stringify!($field)),*],)*
+                }
+            }
+
+            /// Formats the operation with the names of its operand fields, e.g. `(a + b)`.
+            pub fn format_fields(&self) -> String {
+                match self {
+                    ExprOpRef::Var { name } => name.clone(),
+                    ExprOpRef::Inf => "INF".to_string(),
+                    ExprOpRef::NegInf => "-INF".to_string(),
+                    $(
+                        ExprOpRef::$name { .. } => {
+                            $(let $field = FieldName(stringify!($field));)*
+                            format!($format)
+                        },
+                    )*
+                }
+            }
+
+            /// The operand ids of this operation, in field order.
+            pub fn children(&self) -> Vec<usize> {
+                match self {
+                    ExprOpRef::Var { .. } | ExprOpRef::Inf | ExprOpRef::NegInf => vec![],
+                    $(
+                        ExprOpRef::$name { $($field,)* } => vec![$(*$field,)*],
+                    )*
+                }
+            }
+
+            /// The operation with every operand id replaced by `child_id(id)`.
+            pub fn map_children(&self, mut child_id: impl FnMut(usize) -> usize) -> ExprOpRef {
+                match self {
+                    ExprOpRef::Var { name } => ExprOpRef::Var { name: name.clone() },
+                    ExprOpRef::Inf => ExprOpRef::Inf,
+                    ExprOpRef::NegInf => ExprOpRef::NegInf,
+                    $(
+                        ExprOpRef::$name { $($field,)* } => ExprOpRef::$name {
+                            $($field: child_id(*$field),)*
+                        },
+                    )*
+                }
+            }
+
+            /// The operation with every operand id replaced by the expression `child(id)`.
+            pub fn to_expr_op(&self, mut child: impl FnMut(usize) -> Arc<ExprPart>) -> ExprOp {
                 match self {
-                    ExprOp::Var { name } => write!(f, "{name}"),
-                    ExprOp::Inf => write!(f, "INF"),
+                    ExprOpRef::Var { name } => ExprOp::Var { name: name.clone() },
+                    ExprOpRef::Inf => ExprOp::Inf,
+                    ExprOpRef::NegInf => ExprOp::NegInf,
+                    $(
+                        ExprOpRef::$name { $($field,)* } => ExprOp::$name {
+                            $($field: child(*$field),)*
+                        },
+                    )*
+                }
+            }
+
+            /// Creates an operation from operand ids, unused ids are ignored.
+            pub fn from_children(
+                op_id: ExprOpId,
+                child1: usize,
+                child2: usize,
+                child3: usize,
+                child4: usize,
+                child5: usize,
+            ) -> ExprOpRef {
+                let children = [child1, child2, child3, child4, child5];
+                match op_id {
                     $(
-                        // #[serde(rename_all = "camelCase")]
-                        ExprOp::$name { $($field,)* } => {
-                            write!(f, $format)
+                        ExprOpId::$name => ExprOpRef::$name {
+                            $($field: children[child_index!($child_id)],)*
                         },
                     )*
                 }
             }
         }
+
+        impl core::fmt::Debug for ExprOp {
+            fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> std::fmt::Result {
+                write_nested(
+                    self,
+                    HOLE,
+                    |op| Ok(op.format_shallow()),
+                    |part| Ok(format!("{:?}", part)),
+                    |text| f.write_str(text),
+                )
+            }
+        }
     }
 }
 
-#[derive(Serialize, Debug, Clone, Hash, Eq, PartialEq)]
+/// Float literals are compared bitwise, so that `0.0` and `-0.0` stay distinct entries.
+#[derive(Serialize, Debug, Clone)]
 #[serde(untagged)]
 pub enum ExprPartRef {
     IntLiteral(i64),
-    #[serde(with = "F64Def")]
+    #[serde(serialize_with = "serialize_eq_float")]
     FloatLiteral(F64),
     StringLiteral(String),
+    RationalLiteral(Rational),
     Operation(ExprOpRef),
 }
 
-#[derive(Serialize)]
-#[serde(remote = "F64")]
-pub struct F64Def(pub f64);
+impl PartialEq for ExprPartRef {
+    fn eq(&self, other: &Self) -> bool {
+        match (self, other) {
+            (ExprPartRef::IntLiteral(a), ExprPartRef::IntLiteral(b)) => a == b,
+            (ExprPartRef::FloatLiteral(a), ExprPartRef::FloatLiteral(b)) => {
+                a.0.to_bits() == b.0.to_bits()
+            }
+            (ExprPartRef::StringLiteral(a), ExprPartRef::StringLiteral(b)) => a == b,
+            (ExprPartRef::RationalLiteral(a), ExprPartRef::RationalLiteral(b)) => a == b,
+            (ExprPartRef::Operation(a), ExprPartRef::Operation(b)) => a == b,
+            (_, _) => false,
+        }
+    }
+}
 
-#[derive(Serialize, Clone, PartialEq)]
-#[serde(untagged)]
+impl Eq for ExprPartRef {}
+
+impl Hash for ExprPartRef {
+    fn hash<H: Hasher>(&self, state: &mut H) {
+        std::mem::discriminant(self).hash(state);
+        match self {
+            ExprPartRef::IntLiteral(v) => v.hash(state),
+            ExprPartRef::FloatLiteral(v) => v.0.to_bits().hash(state),
+            ExprPartRef::StringLiteral(v) => v.hash(state),
+            ExprPartRef::RationalLiteral(v) => v.hash(state),
+            ExprPartRef::Operation(v) => v.hash(state),
+        }
+    }
+}
+
+#[derive(Clone)]
 pub enum ExprPart {
     IntLiteral(i64),
     FloatLiteral(f64),
     StringLiteral(String),
+    /// Only produced by the optimizer, evaluates to a float.
+    RationalLiteral(Rational),
     Operation(ExprOp),
 }
 
+/// NaN is never a valid expression: it is rejected on construction, never produced by folding,
+/// and is an error during evaluation and serialization.
+#[derive(Debug, Clone, Copy, Eq, PartialEq)]
+pub struct NanError;
+
+impl Display for NanError {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        write!(f, "NaN is not a valid expression")
+    }
+}
+
+impl std::error::Error for NanError {}
+
+impl ExprPart {
+    /// Creates a float literal, infinities become `INF` and `-INF`.
+    pub fn from_f64(value: f64) -> Result<ExprPart, NanError> {
+        if value.is_nan() {
+            Err(NanError)
+        } else if value.is_finite() {
+            Ok(ExprPart::FloatLiteral(value))
+        } else if value.is_sign_positive() {
+            Ok(ExprPart::Operation(ExprOp::Inf))
+        } else {
+            Ok(ExprPart::Operation(ExprOp::NegInf))
+        }
+    }
+
+    /// The expression with every operand replaced by its id, e.g. in an oplist.
+    pub fn to_expr_part_ref(&self, child_id: impl FnMut(&Arc<ExprPart>) -> usize) -> ExprPartRef {
+        match self {
+            ExprPart::IntLiteral(v) => ExprPartRef::IntLiteral(*v),
+            ExprPart::FloatLiteral(v) => ExprPartRef::FloatLiteral((*v).into()),
+            ExprPart::StringLiteral(v) => ExprPartRef::StringLiteral(v.clone()),
+            ExprPart::RationalLiteral(v) => ExprPartRef::RationalLiteral(*v),
+            ExprPart::Operation(op) => ExprPartRef::Operation(op.to_expr_op_ref(child_id)),
+        }
+    }
+
+    /// Every distinct node of the expression, each after its operands.
+    ///
+    /// Like every traversal of expressions, this uses an explicit stack instead of recursion,
+    /// so that deep expressions, e.g. sums built in a loop, can't overflow the stack.
+    pub fn post_order(&self) -> Vec<&ExprPart> {
+        self.post_order_except(|_| false)
+    }
+
+    /// Like `post_order`, but nodes for which `known` is true are skipped along with their operands.
+    pub(crate) fn post_order_except(&self, known: impl Fn(&ExprPart) -> bool) -> Vec<&ExprPart> {
+        let mut order = Vec::new();
+        let mut seen = HashSet::new();
+        let mut stack = vec![(self, false)];
+        while let Some((expr, visited_operands)) = stack.pop() {
+            if visited_operands {
+                order.push(expr);
+                continue;
+            }
+            if !seen.insert(expr as *const ExprPart) || known(expr) {
+                continue;
+            }
+            stack.push((expr, true));
+            if let ExprPart::Operation(op) = expr {
+                stack.extend(
+                    op.children()
+                        .into_iter()
+                        .rev()
+                        .map(|child| (&**child, false)),
+                );
+            }
+        }
+        order
+    }
+
+    /// Formats the expression as JSON, e.g. `{"type":"add","a":{"type":"var","name":"x"},"b":1}`.
+    pub fn to_json(&self) -> serde_json::Result<String> {
+        let shallow =
+            |expr: &ExprPart| serde_json::to_string(&expr.to_expr_part_ref(|_| JSON_HOLE));
+        match self {
+            ExprPart::Operation(op) => {
+                let mut json = String::new();
+                write_nested(
+                    op,
+                    &JSON_HOLE.to_string(),
+                    |op| serde_json::to_string(&op.to_expr_op_ref(|_| JSON_HOLE)),
+                    shallow,
+                    |text| {
+                        json.push_str(text);
+                        Ok(())
+                    },
+                )?;
+                Ok(json)
+            }
+            _ => shallow(self),
+        }
+    }
+}
+
+impl ExprPartRef {
+    /// The operand ids of this node, in field order.
+    pub fn children(&self) -> Vec<usize> {
+        match self {
+            ExprPartRef::Operation(op) => op.children(),
+            _ => vec![],
+        }
+    }
+
+    /// The node with every operand id replaced by `child_id(id)`.
+    pub fn map_children(&self, child_id: impl FnMut(usize) -> usize) -> ExprPartRef {
+        match self {
+            ExprPartRef::Operation(op) => ExprPartRef::Operation(op.map_children(child_id)),
+            _ => self.clone(),
+        }
+    }
+
+    /// The node with every operand id replaced by the expression `child(id)`.
+    pub fn to_expr_part(&self, child: impl FnMut(usize) -> Arc<ExprPart>) -> ExprPart {
+        match self {
+            ExprPartRef::IntLiteral(v) => ExprPart::IntLiteral(*v),
+            ExprPartRef::FloatLiteral(v) => ExprPart::FloatLiteral(v.0),
+            ExprPartRef::StringLiteral(v) => ExprPart::StringLiteral(v.clone()),
+            ExprPartRef::RationalLiteral(v) => ExprPart::RationalLiteral(*v),
+            ExprPartRef::Operation(op) => ExprPart::Operation(op.to_expr_op(child)),
+        }
+    }
+}
+
+/// Deserialized from the JSON format of oplist entries.
+/// Entries are buffered as JSON values first, so that rationals can be told apart from operations.
+impl<'de> Deserialize<'de> for ExprPartRef {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        let value = serde_json::Value::deserialize(deserializer)?;
+        ExprPartRef::from_json(value).map_err(D::Error::custom)
+    }
+}
+
+impl ExprPartRef {
+    pub(crate) fn from_json(value: serde_json::Value) -> Result<ExprPartRef, String> {
+        use serde_json::Value;
+
+        match value {
+            Value::Number(v) => match (v.as_i64(), v.as_f64()) {
+                (Some(v), _) => Ok(ExprPartRef::IntLiteral(v)),
+                (None, _) if v.is_u64() => Err(format!("int literal out of range: {v}")),
+                (None, Some(v)) => Ok(ExprPartRef::FloatLiteral(v.into())),
+                (None, None) => Err(format!("invalid number: {v}")),
+            },
+            Value::String(v) => Ok(ExprPartRef::StringLiteral(v)),
+            Value::Object(obj) if obj.get("type").and_then(Value::as_str) == Some("rational") => {
+                let field = |name: &str| {
+                    obj.get(name)
+                        .and_then(Value::as_i64)
+                        .ok_or_else(|| format!("rational `{name}` must be an int"))
+                };
+                if obj.len() != 3 {
+                    return Err("a rational has only the fields `num` and `den`".to_string());
+                }
+                Rational::new(field("num")?, field("den")?)
+                    .map(ExprPartRef::RationalLiteral)
+                    .ok_or_else(|| "rational `den` must not be 0".to_string())
+            }
+            Value::Object(_) => ExprOpRef::deserialize(value)
+                .map(ExprPartRef::Operation)
+                .map_err(|err| err.to_string()),
+            value => Err(format!("expected a literal or an operation, found {value}")),
+        }
+    }
+}
+
+/// The indices of every node `root` depends on, including itself, in increasing order.
+/// Operands must have smaller indices than the nodes using them, so every node comes after them.
+pub(crate) fn reachable(nodes: &[ExprPartRef], root: usize) -> Vec<usize> {
+    let mut seen = vec![false; root + 1];
+    let mut stack = vec![root];
+    while let Some(idx) = stack.pop() {
+        if !seen[idx] {
+            seen[idx] = true;
+            stack.extend(nodes[idx].children());
+        }
+    }
+    (0..seen.len()).filter(|&idx| seen[idx]).collect()
+}
+
+/// Builds the expression of `nodes[root]`, passing every node through `finish` once it is built.
+/// Operands must have smaller indices than the nodes using them.
+pub(crate) fn build_expr(
+    nodes: &[ExprPartRef],
+    root: usize,
+    mut finish: impl FnMut(Arc<ExprPart>) -> Arc<ExprPart>,
+) -> Arc<ExprPart> {
+    let mut exprs: Vec<Option<Arc<ExprPart>>> = vec![None; root + 1];
+    for idx in reachable(nodes, root) {
+        let expr = nodes[idx].to_expr_part(|child| exprs[child].clone().unwrap());
+        exprs[idx] = Some(finish(Arc::new(expr)));
+    }
+    exprs[root].take().unwrap()
+}
+
+/// Builds the expression of every node, in the order of `nodes`, passing every node through
+/// `finish` once it is built. Operands must have smaller indices than the nodes using them.
+pub(crate) fn build_all(
+    nodes: &[ExprPartRef],
+    mut finish: impl FnMut(Arc<ExprPart>) -> Arc<ExprPart>,
+) -> Vec<Arc<ExprPart>> {
+    let mut exprs: Vec<Arc<ExprPart>> = Vec::with_capacity(nodes.len());
+    for node in nodes {
+        let expr = node.to_expr_part(|child| exprs[child].clone());
+        exprs.push(finish(Arc::new(expr)));
+    }
+    exprs
+}
+
+/// Stands in for the operands of a shallow formatted operation.
+struct Hole;
+
+const HOLE: &str = "\0";
+
+impl Debug for Hole {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        f.write_str(HOLE)
+    }
+}
+
+/// Converts the name of a variant to its name in JSON, e.g. `MeasureTextX` to `measureTextX`.
+fn lower_camel_case(name: &str) -> String {
+    name[..1].to_lowercase() + &name[1..]
+}
+
+/// Converts the name of a field to its name in JSON, e.g. `font_size` to `fontSize`.
+fn field_camel_case(name: &str) -> String {
+    let mut words = name.split('_');
+    let first = words.next().unwrap_or_default().to_string();
+    words.fold(first, |camel, word| {
+        camel + &word[..1].to_uppercase() + &word[1..]
+    })
+}
+
+/// Stands in for an operand by the name of its field, e.g. `a`.
+struct FieldName(&'static str);
+
+impl Debug for FieldName {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        f.write_str(self.0)
+    }
+}
+
+/// Stands in for the operand ids of a shallow serialized operation.
+/// Only operations without operands contain strings, so it can't be confused with anything else.
+const JSON_HOLE: usize = usize::MAX;
+
+/// Writes a nested operation in order. `shallow_op` renders an operation with every operand
+/// replaced by `hole`, and `leaf` renders any other operand.
+fn write_nested<E>(
+    op: &ExprOp,
+    hole: &str,
+    mut shallow_op: impl FnMut(&ExprOp) -> Result<String, E>,
+    mut leaf: impl FnMut(&ExprPart) -> Result<String, E>,
+    mut write: impl FnMut(&str) -> Result<(), E>,
+) -> Result<(), E> {
+    enum Piece<'a> {
+        Text(String),
+        Op(&'a ExprOp),
+    }
+
+    let mut stack = vec![Piece::Op(op)];
+    while let Some(piece) = stack.pop() {
+        let op = match piece {
+            Piece::Text(text) => {
+                write(&text)?;
+                continue;
+            }
+            Piece::Op(op) => op,
+        };
+        let children = op.children();
+        let shallow = shallow_op(op)?;
+        let mut texts: Vec<&str> = shallow.splitn(children.len() + 1, hole).collect();
+        let last = texts.pop().unwrap_or_default();
+        stack.push(Piece::Text(last.to_string()));
+        for (text, child) in texts.into_iter().zip(children).rev() {
+            stack.push(match &**child {
+                ExprPart::Operation(op) => Piece::Op(op),
+                child => Piece::Text(leaf(child)?),
+            });
+            stack.push(Piece::Text(text.to_string()));
+        }
+    }
+    Ok(())
+}
+
+/// Serialized as the JSON from `to_json`, which writes nested expressions with an explicit stack.
+impl Serialize for ExprPart {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        let json = self.to_json().map_err(S::Error::custom)?;
+        RawValue::from_string(json)
+            .map_err(S::Error::custom)?
+            .serialize(serializer)
+    }
+}
+
+impl PartialEq for ExprPart {
+    fn eq(&self, other: &Self) -> bool {
+        let mut stack = vec![(self, other)];
+        while let Some((a, b)) = stack.pop() {
+            // Shared subexpressions are compared once, NaN literals are invalid anyway
+            if std::ptr::eq(a, b) {
+                continue;
+            }
+            let equal = match (a, b) {
+                (ExprPart::IntLiteral(a), ExprPart::IntLiteral(b)) => a == b,
+                (ExprPart::FloatLiteral(a), ExprPart::FloatLiteral(b)) => a == b,
+                (ExprPart::StringLiteral(a), ExprPart::StringLiteral(b)) => a == b,
+                (ExprPart::RationalLiteral(a), ExprPart::RationalLiteral(b)) => a == b,
+                (
+                    ExprPart::Operation(ExprOp::Var { name: a }),
+                    ExprPart::Operation(ExprOp::Var { name: b }),
+                ) => a == b,
+                (ExprPart::Operation(a), ExprPart::Operation(b)) => {
+                    let same_op = std::mem::discriminant(a) == std::mem::discriminant(b);
+                    if same_op {
+                        stack.extend(
+                            a.children()
+                                .into_iter()
+                                .zip(b.children())
+                                .map(|(a, b)| (&**a, &**b)),
+                        );
+                    }
+                    same_op
+                }
+                (_, _) => false,
+            };
+            if !equal {
+                return false;
+            }
+        }
+        true
+    }
+}
+
+impl Drop for ExprPart {
+    fn drop(&mut self) {
+        // Operands are moved to a stack and dropped one by one, instead of recursively dropping
+        // the whole expression. Whichever reference to an operand is the last one unlinks its
+        // operands in turn, so operands shared within the expression are handled too.
+        let children = match self {
+            ExprPart::Operation(op) => op.children_mut(),
+            _ => return,
+        };
+        if !children
+            .iter()
+            .any(|child| matches!(***child, ExprPart::Operation(_)))
+        {
+            return;
+        }
+
+        // Unlinked operations keep the placeholder, so dropping them doesn't unlink anything again
+        let placeholder = Arc::new(ExprPart::IntLiteral(0));
+        let unlink = |children: Vec<&mut Arc<ExprPart>>, stack: &mut Vec<Arc<ExprPart>>| {
+            for child in children {
+                stack.push(std::mem::replace(child, placeholder.clone()));
+            }
+        };
+        let mut stack = Vec::new();
+        unlink(children, &mut stack);
+        while let Some(child) = stack.pop() {
+            // Only the last strong reference gets the operation, weak references, e.g. from the
+            // interner, don't keep it alive
+            if let Some(ExprPart::Operation(op)) = Arc::into_inner(child).as_mut() {
+                unlink(op.children_mut(), &mut stack);
+            }
+        }
+    }
+}
+
+// JSON has no representation for non-finite floats, they would silently become `null`
+fn serialize_float<S: Serializer>(value: &f64, serializer: S) -> Result<S::Ok, S::Error> {
+    if value.is_nan() {
+        Err(S::Error::custom(NanError))
+    } else if !value.is_finite() {
+        Err(S::Error::custom(
+            "infinite float literals must be `INF` or `-INF`",
+        ))
+    } else {
+        serializer.serialize_f64(*value)
+    }
+}
+
+fn serialize_eq_float<S: Serializer>(value: &F64, serializer: S) -> Result<S::Ok, S::Error> {
+    serialize_float(&value.0, serializer)
+}
+
 impl Debug for ExprPart {
     fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
         match self {
             ExprPart::IntLiteral(val) => write!(f, "{}", val),
             ExprPart::FloatLiteral(val) => write!(f, "{}", val),
             ExprPart::StringLiteral(val) => write!(f, "{:?}", val),
+            ExprPart::RationalLiteral(val) => write!(f, "{:?}", val),
             ExprPart::Operation(val) => write!(f, "{:?}", val),
         }
     }
@@ -337,5 +925,92 @@ define_ops! {
             f: ExprPart = child3,
         },
         format: "if ({cond:?}) {{{t:?}}} else {{{f:?}}}",
+    },
+    Concat: {
+        id: 24,
+        fields: {
+            a: ExprPart = child1,
+            b: ExprPart = child2,
+        },
+        format: "concat({a:?}, {b:?})",
+    },
+    StrLen: {
+        id: 25,
+        fields: {
+            a: ExprPart = child1,
+        },
+        format: "strLen({a:?})",
+    },
+    Substr: {
+        id: 26,
+        fields: {
+            text: ExprPart = child1,
+            start: ExprPart = child2,
+            end: ExprPart = child3,
+        },
+        format: "substr({text:?}, {start:?}, {end:?})",
+    },
+    Format: {
+        id: 27,
+        fields: {
+            template: ExprPart = child1,
+            a: ExprPart = child2,
+        },
+        format: "format({template:?}, {a:?})",
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn var(name: &str) -> Arc<ExprPart> {
+        Arc::new(ExprPart::Operation(ExprOp::Var {
+            name: name.to_string(),
+        }))
+    }
+
+    fn add(a: Arc<ExprPart>, b: Arc<ExprPart>) -> Arc<ExprPart> {
+        Arc::new(ExprPart::Operation(ExprOp::Add { a, b }))
+    }
+
+    #[test]
+    fn nan_is_rejected() {
+        assert_eq!(ExprPart::from_f64(f64::NAN), Err(NanError));
+        assert_eq!(ExprPart::from_f64(-f64::NAN), Err(NanError));
+        // Float literals can still be built directly, but never serialized
+        assert!(serde_json::to_string(&ExprPart::FloatLiteral(f64::NAN)).is_err());
+        assert!(
+            serde_json::to_string(&add(var("x"), Arc::new(ExprPart::FloatLiteral(f64::NAN))))
+                .is_err()
+        );
+    }
+
+    #[test]
+    fn drop_deep_chain() {
+        let mut total = var("w0");
+        for i in 1..1_000_000 {
+            total = add(total, var(&format!("w{}", i)));
+        }
+        drop(total);
+    }
+
+    #[test]
+    fn drop_deep_shared() {
+        // Every node references its operand twice, so no reference is unique until the end
+        let mut total = var("x");
+        for _ in 0..1_000_000 {
+            total = add(total.clone(), total);
+        }
+        drop(total);
+    }
+
+    #[test]
+    fn drop_keeps_shared_operands() {
+        let x = add(var("x"), var("y"));
+        let total = add(add(x.clone(), x.clone()), x.clone());
+        drop(total);
+        assert_eq!(Arc::strong_count(&x), 1);
+        assert_eq!(format!("{:?}", x), "(x + y)");
     }
 }
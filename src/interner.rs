@@ -0,0 +1,107 @@
+use crate::expressions::{ExprPart, ExprPartRef};
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex, OnceLock, Weak};
+
+/// Identifies a node by its contents and the addresses of its operands, which are interned too.
+#[derive(Hash, Eq, PartialEq)]
+enum Key {
+    /// Floats are compared bitwise, so that `0.0` and `-0.0` stay distinct.
+    Float(u64),
+    Part(ExprPartRef),
+}
+
+impl Key {
+    fn of(expr: &ExprPart) -> Key {
+        match expr {
+            ExprPart::FloatLiteral(v) => Key::Float(v.to_bits()),
+            _ => Key::Part(expr.to_expr_part_ref(|child| Arc::as_ptr(child) as usize)),
+        }
+    }
+}
+
+/// The number of nodes below which dead nodes are never swept.
+const MIN_SWEEP_LEN: usize = 1024;
+
+#[derive(Default)]
+struct Table {
+    /// Nodes are only weakly referenced, so that the table doesn't keep expressions alive.
+    nodes: HashMap<Key, Weak<ExprPart>>,
+    /// The number of nodes left after the last sweep of dead nodes.
+    swept_len: usize,
+}
+
+impl Table {
+    fn get(&self, key: &Key) -> Option<Arc<ExprPart>> {
+        self.nodes.get(key).and_then(Weak::upgrade)
+    }
+
+    fn insert(&mut self, key: Key, expr: &Arc<ExprPart>) {
+        // Dead nodes are swept whenever the table doubles in size, so sweeping is amortized O(1)
+        if self.nodes.len() >= (self.swept_len * 2).max(MIN_SWEEP_LEN) {
+            self.nodes.retain(|_, node| node.strong_count() > 0);
+            self.swept_len = self.nodes.len();
+        }
+        self.nodes.insert(key, Arc::downgrade(expr));
+    }
+
+    fn intern(&mut self, expr: Arc<ExprPart>) -> Arc<ExprPart> {
+        // Operands are interned before the operations using them, with an explicit stack so that
+        // deep expressions can't overflow the stack. Interned subexpressions are never entered,
+        // since their operands are interned too.
+        let mut interned: HashMap<*const ExprPart, Arc<ExprPart>> = HashMap::new();
+        let mut stack = vec![(&*expr, false)];
+        while let Some((node, visited_operands)) = stack.pop() {
+            if interned.contains_key(&(node as *const ExprPart)) {
+                continue;
+            }
+
+            if !visited_operands {
+                if let Some(existing) = self.get(&Key::of(node)) {
+                    interned.insert(node, existing);
+                    continue;
+                }
+                stack.push((node, true));
+                if let ExprPart::Operation(op) = node {
+                    stack.extend(
+                        op.children()
+                            .into_iter()
+                            .rev()
+                            .map(|child| (&**child, false)),
+                    );
+                }
+                continue;
+            }
+
+            let mut part = node.clone();
+            if let ExprPart::Operation(op) = &mut part {
+                for child in op.children_mut() {
+                    *child = interned[&Arc::as_ptr(child)].clone();
+                }
+            }
+            let key = Key::of(&part);
+            let part = match self.get(&key) {
+                Some(existing) => existing,
+                None => {
+                    let part = Arc::new(part);
+                    self.insert(key, &part);
+                    part
+                }
+            };
+            interned.insert(node, part);
+        }
+        interned[&Arc::as_ptr(&expr)].clone()
+    }
+}
+
+fn table() -> &'static Mutex<Table> {
+    static TABLE: OnceLock<Mutex<Table>> = OnceLock::new();
+    TABLE.get_or_init(Mutex::default)
+}
+
+/// Returns the node shared by every expression structurally equal to `expr`.
+///
+/// Every expression built through the C API or returned by the optimizer is interned, so interned
+/// expressions are equal exactly when they are the same node, and can be compared by pointer.
+pub fn intern(expr: Arc<ExprPart>) -> Arc<ExprPart> {
+    table().lock().unwrap().intern(expr)
+}
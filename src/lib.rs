@@ -1,7 +1,18 @@
 #![feature(vec_into_raw_parts)]
 #![feature(concat_idents)]
 
+pub mod arena;
 pub mod c_api;
+pub mod content_hash;
+pub mod cost;
+pub mod dot;
+pub mod evaluator;
 pub mod expressions;
+pub mod interner;
 pub mod operation_list;
 pub mod optimizer;
+pub mod patch;
+pub mod rational;
+pub mod stats;
+pub mod typecheck;
+pub mod variables;
@@ -1,11 +1,126 @@
-use crate::expressions::{ExprPart, ExprPartRef};
-use serde::{Serialize, Serializer};
-use std::collections::HashMap;
+use crate::arena::{Arena, NodeId};
+use crate::c_api;
+use crate::expressions::{self, ExprOpRef, ExprPart, ExprPartRef};
+use crate::interner;
+use crate::optimizer::{self, OptimizerOptions};
+use serde::de::Error;
+use serde::{Deserialize, Deserializer, Serialize, Serializer};
+use std::collections::{BTreeMap, HashMap};
+use std::fmt::{Display, Formatter};
+use std::sync::Arc;
+
+#[derive(Serialize, Debug, Clone, Eq, PartialEq)]
+#[serde(tag = "type", rename_all = "camelCase")]
+pub enum OplistError {
+    /// An entry whose operand isn't an earlier entry.
+    InvalidOperand { id: usize, operand: usize },
+    /// Entries that depend on each other, each one having the next one as an operand, and the last
+    /// one having the first one.
+    Cycle { ids: Vec<usize> },
+    /// A float literal that is infinite or NaN, infinities must be `INF` and `-INF` operations.
+    NonFiniteLiteral { id: usize },
+    /// An entry that `ops_set` doesn't contain.
+    MissingFromSet { id: usize },
+    /// An `ops_set` entry whose id isn't an identical entry.
+    StaleSetEntry { id: usize },
+    /// A named output that isn't an entry.
+    InvalidOutput { name: String, id: usize },
+    /// A serialization format this version of the crate can't read.
+    UnsupportedFormat {
+        version: u32,
+        simplexp_version: String,
+    },
+    /// An operation whose fields aren't the ones this version of the crate has.
+    IncompatibleOp {
+        op: String,
+        simplexp_version: String,
+    },
+    /// A deserialized entry whose content hash isn't the serialized one.
+    ContentHashMismatch { id: usize, hash: String },
+    /// A range of entries removed by a patch that doesn't exist or isn't after the previous range.
+    InvalidRemoval { start: usize, end: usize },
+    /// An entry kept by a patch whose operand is removed.
+    RemovedOperand { id: usize, operand: usize },
+    /// A named output whose entry is removed by a patch, without the patch changing the output.
+    RemovedOutput { name: String, id: usize },
+}
+
+impl Display for OplistError {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        match self {
+            OplistError::InvalidOperand { id, operand } => write!(
+                f,
+                "entry {} has operand {}, operands must be earlier entries",
+                id, operand
+            ),
+            OplistError::Cycle { ids } => write!(f, "entries {:?} are operands of each other", ids),
+            OplistError::NonFiniteLiteral { id } => {
+                write!(f, "entry {} is a float literal that isn't finite", id)
+            }
+            OplistError::MissingFromSet { id } => write!(f, "entry {} is missing from ops_set", id),
+            OplistError::StaleSetEntry { id } => write!(
+                f,
+                "ops_set has an entry for {}, which is a different entry or doesn't exist",
+                id
+            ),
+            OplistError::InvalidOutput { name, id } => {
+                write!(f, "output {:?} is entry {}, which doesn't exist", name, id)
+            }
+            OplistError::UnsupportedFormat {
+                version,
+                simplexp_version,
+            } => write!(
+                f,
+                "oplist format version {} from simplexp {} isn't supported, expected version {}",
+                version, simplexp_version, FORMAT_VERSION
+            ),
+            OplistError::IncompatibleOp {
+                op,
+                simplexp_version,
+            } => write!(
+                f,
+                "operation {:?} from simplexp {} has fields this version doesn't have",
+                op, simplexp_version
+            ),
+            OplistError::ContentHashMismatch { id, hash } => {
+                write!(f, "entry {} doesn't have the content hash {:?}", id, hash)
+            }
+            OplistError::InvalidRemoval { start, end } => write!(
+                f,
+                "removed entries {}..{} don't exist or overlap earlier removed entries",
+                start, end
+            ),
+            OplistError::RemovedOperand { id, operand } => write!(
+                f,
+                "entry {} has operand {}, which the patch removes",
+                id, operand
+            ),
+            OplistError::RemovedOutput { name, id } => write!(
+                f,
+                "output {:?} is entry {}, which the patch removes",
+                name, id
+            ),
+        }
+    }
+}
+
+impl std::error::Error for OplistError {}
 
 #[derive(Clone, Debug)]
 pub struct OperationList {
     pub ops: Vec<ExprPartRef>,
     pub ops_set: HashMap<ExprPartRef, usize>,
+    /// The ids of named entries, e.g. `"rect.left"`.
+    pub outputs: BTreeMap<String, usize>,
+    /// Arbitrary JSON included in the serialization, e.g. which screen the oplist belongs to.
+    pub metadata: Option<serde_json::Value>,
+    /// Whether the serialization includes the content hash of every entry, see `content_hashes`.
+    /// Deserializing an oplist that includes them sets it.
+    pub serialize_hashes: bool,
+    /// The number of nodes added by `add`, `add_output` and `add_from_arena`.
+    pub(crate) added_nodes: usize,
+    /// The number of added nodes that were already entries.
+    pub(crate) reused_nodes: usize,
 }
 
 impl OperationList {
@@ -13,6 +128,11 @@ impl OperationList {
         OperationList {
             ops: Vec::new(),
             ops_set: HashMap::new(),
+            outputs: BTreeMap::new(),
+            metadata: None,
+            serialize_hashes: false,
+            added_nodes: 0,
+            reused_nodes: 0,
         }
     }
 
@@ -22,14 +142,319 @@ impl OperationList {
         ops
     }
 
-    pub fn add(&mut self, expr: &ExprPart) -> usize {
-        let expr_ref = match expr {
-            ExprPart::IntLiteral(v) => ExprPartRef::IntLiteral(*v),
-            ExprPart::FloatLiteral(v) => ExprPartRef::FloatLiteral((*v).into()),
-            ExprPart::StringLiteral(v) => ExprPartRef::StringLiteral(v.clone()),
-            ExprPart::Operation(op) => ExprPartRef::Operation(op.to_expr_op_ref(self)),
+    /// Makes an oplist of existing entries, e.g. deserialized ones, whose operands must be earlier
+    /// entries. Duplicate entries are kept, with later duplicates referring to the first one.
+    pub fn from_ops(ops: Vec<ExprPartRef>) -> Result<Self, OplistError> {
+        let mut ops_set = HashMap::with_capacity(ops.len());
+        for (id, op) in ops.iter().enumerate() {
+            if let Some(operand) = op.children().into_iter().find(|&child| child >= id) {
+                return Err(OplistError::InvalidOperand { id, operand });
+            }
+            ops_set.entry(op.clone()).or_insert(id);
+        }
+        Ok(OperationList {
+            ops,
+            ops_set,
+            ..OperationList::new()
+        })
+    }
+
+    /// Checks every invariant of the oplist, for oplists that were edited through the public
+    /// fields. Returns every problem found, or nothing if the oplist is valid.
+    pub fn validate(&self) -> Vec<OplistError> {
+        let mut errors = Vec::new();
+        for (id, op) in self.ops.iter().enumerate() {
+            for operand in op.children() {
+                if operand >= id {
+                    errors.push(OplistError::InvalidOperand { id, operand });
+                }
+            }
+            if let ExprPartRef::FloatLiteral(v) = op {
+                if !v.0.is_finite() {
+                    errors.push(OplistError::NonFiniteLiteral { id });
+                }
+            }
+            if !self.ops_set.contains_key(op) {
+                errors.push(OplistError::MissingFromSet { id });
+            }
+        }
+
+        let mut stale: Vec<usize> = self
+            .ops_set
+            .iter()
+            .filter(|&(op, &id)| self.ops.get(id) != Some(op))
+            .map(|(_, &id)| id)
+            .collect();
+        stale.sort_unstable();
+        errors.extend(
+            stale
+                .into_iter()
+                .map(|id| OplistError::StaleSetEntry { id }),
+        );
+
+        // Only operands that aren't earlier entries can form cycles
+        if errors
+            .iter()
+            .any(|err| matches!(err, OplistError::InvalidOperand { .. }))
+        {
+            errors.extend(
+                self.cycles()
+                    .into_iter()
+                    .map(|ids| OplistError::Cycle { ids }),
+            );
+        }
+
+        for (name, &id) in &self.outputs {
+            if id >= self.ops.len() {
+                errors.push(OplistError::InvalidOutput {
+                    name: name.clone(),
+                    id,
+                });
+            }
+        }
+        errors
+    }
+
+    /// Finds cycles of entries with a depth-first search, one cycle for every operand leading back
+    /// to an entry being searched.
+    fn cycles(&self) -> Vec<Vec<usize>> {
+        let operands = |id: usize| -> Vec<usize> {
+            let mut operands = self.ops[id].children();
+            operands.retain(|&operand| operand < self.ops.len());
+            operands
         };
 
+        let mut on_path = vec![false; self.ops.len()];
+        let mut done = vec![false; self.ops.len()];
+        let mut cycles = Vec::new();
+        for start in 0..self.ops.len() {
+            if done[start] {
+                continue;
+            }
+            on_path[start] = true;
+            let mut path = vec![(start, operands(start))];
+            while let Some((id, remaining)) = path.last_mut() {
+                let id = *id;
+                match remaining.pop() {
+                    Some(operand) if on_path[operand] => {
+                        let pos = path.iter().position(|&(id, _)| id == operand).unwrap();
+                        cycles.push(path[pos..].iter().map(|&(id, _)| id).collect());
+                    }
+                    Some(operand) if !done[operand] => {
+                        on_path[operand] = true;
+                        path.push((operand, operands(operand)));
+                    }
+                    Some(_) => {}
+                    None => {
+                        on_path[id] = false;
+                        done[id] = true;
+                        path.pop();
+                    }
+                }
+            }
+        }
+        cycles
+    }
+
+    /// The id of a named output.
+    pub fn output(&self, name: &str) -> Option<usize> {
+        self.outputs.get(name).copied()
+    }
+
+    /// Names an entry, replacing the output previously named `name`.
+    pub fn set_output(&mut self, name: &str, id: usize) -> Result<(), OplistError> {
+        if id >= self.ops.len() {
+            return Err(OplistError::InvalidOutput {
+                name: name.to_string(),
+                id,
+            });
+        }
+        self.outputs.insert(name.to_string(), id);
+        Ok(())
+    }
+
+    /// Adds an expression as the output named `name`, replacing the output previously named so.
+    pub fn add_output(&mut self, name: &str, expr: &ExprPart) -> usize {
+        let id = self.add(expr);
+        self.outputs.insert(name.to_string(), id);
+        id
+    }
+
+    /// Converts an entry and the entries it depends on into an interned expression.
+    ///
+    /// # Panics
+    ///
+    /// Panics if there's no such entry.
+    pub fn to_expr(&self, id: usize) -> Arc<ExprPart> {
+        interner::intern(expressions::build_expr(&self.ops, id, |expr| expr))
+    }
+
+    pub fn add(&mut self, expr: &ExprPart) -> usize {
+        self.add_shared(expr, &mut HashMap::new())
+    }
+
+    /// Adds an expression, skipping the nodes already in `ids`, and records the ids of added nodes.
+    fn add_shared(&mut self, expr: &ExprPart, ids: &mut HashMap<*const ExprPart, usize>) -> usize {
+        // Operands are added before the operations using them, shared ones only once
+        for node in expr.post_order_except(|node| ids.contains_key(&(node as *const ExprPart))) {
+            let expr_ref = node.to_expr_part_ref(|child| ids[&Arc::as_ptr(child)]);
+            let id = self.insert_added(expr_ref);
+            ids.insert(node, id);
+        }
+        ids[&(expr as *const ExprPart)]
+    }
+
+    /// Optimizes every entry again, now that its operands are known, and merges entries that
+    /// became equivalent. Each entry is optimized like building it through the C API would, so
+    /// optimizations spanning entries added separately are found too.
+    ///
+    /// Returns the new id of every old id. Entries that are no longer used are kept, `compact`
+    /// drops them.
+    pub fn optimize(&mut self, options: &OptimizerOptions) -> Vec<usize> {
+        let exprs =
+            expressions::build_all(&self.ops, |expr| optimizer::optimize_with(expr, options));
+
+        // Optimized expressions are interned, so equivalent entries are now the same node
+        let mut optimized = OperationList::new();
+        let mut added = HashMap::new();
+        let ids: Vec<usize> = exprs
+            .iter()
+            .map(|expr| optimized.add_shared(expr, &mut added))
+            .collect();
+        self.ops = optimized.ops;
+        self.ops_set = optimized.ops_set;
+        for id in self.outputs.values_mut() {
+            *id = ids[*id];
+        }
+        ids
+    }
+
+    /// Adds a node of an arena and every node it depends on, returning its id in the oplist.
+    pub fn add_from_arena(&mut self, arena: &Arena, id: NodeId) -> usize {
+        let mut ids: HashMap<usize, usize> = HashMap::new();
+        for node_id in arena.reachable(id) {
+            let expr_ref = arena.get(node_id).map_children(|child| ids[&child]);
+            let idx = self.insert_added(expr_ref);
+            ids.insert(node_id.index(), idx);
+        }
+        ids[&id.index()]
+    }
+
+    /// Drops every entry that neither `roots` nor the named outputs depend on, and renumbers the
+    /// rest, keeping their order. Entries that became identical are merged.
+    ///
+    /// Returns the new id of every old id, or `None` for dropped entries.
+    ///
+    /// # Panics
+    ///
+    /// Panics if a root isn't an entry.
+    pub fn compact(&mut self, roots: &[usize]) -> Vec<Option<usize>> {
+        let roots: Vec<usize> = roots.iter().chain(self.outputs.values()).copied().collect();
+        let live = self.live(&roots);
+        let ops = std::mem::take(&mut self.ops);
+        self.ops_set.clear();
+        let mut ids = vec![None; ops.len()];
+        for (id, op) in ops.into_iter().enumerate() {
+            if live[id] {
+                ids[id] = Some(self.insert(op.map_children(|child| ids[child].unwrap())));
+            }
+        }
+        for id in self.outputs.values_mut() {
+            *id = ids[*id].unwrap();
+        }
+        ids
+    }
+
+    /// Copies the entries the roots depend on, including the roots, into a new oplist, along
+    /// with the named outputs of copied entries. Entries keep their order.
+    ///
+    /// Returns the new oplist, and the id in it of every entry of this oplist, or `None` for
+    /// entries that weren't copied.
+    ///
+    /// # Panics
+    ///
+    /// Panics if a root isn't an entry.
+    pub fn extract(&self, roots: &[usize]) -> (OperationList, Vec<Option<usize>>) {
+        let live = self.live(roots);
+        let mut oplist = OperationList::new();
+        let mut ids = vec![None; self.ops.len()];
+        for (id, op) in self.ops.iter().enumerate() {
+            if live[id] {
+                ids[id] = Some(oplist.insert(op.map_children(|child| ids[child].unwrap())));
+            }
+        }
+        for (name, &id) in &self.outputs {
+            if let Some(new_id) = ids[id] {
+                oplist.outputs.insert(name.clone(), new_id);
+            }
+        }
+        oplist.metadata = self.metadata.clone();
+        oplist.serialize_hashes = self.serialize_hashes;
+        (oplist, ids)
+    }
+
+    /// Whether each entry is one of the roots or one of their dependencies.
+    fn live(&self, roots: &[usize]) -> Vec<bool> {
+        let mut live = vec![false; self.ops.len()];
+        for &root in roots {
+            live[root] = true;
+        }
+        // Operands are earlier entries, so a single backwards pass finds every live entry
+        for id in (0..self.ops.len()).rev() {
+            if live[id] {
+                for child in self.ops[id].children() {
+                    live[child] = true;
+                }
+            }
+        }
+        live
+    }
+
+    /// Groups the entries into levels, each entry in the level after the last level of its
+    /// operands. Once the previous levels are evaluated, the entries of a level can be evaluated
+    /// in parallel.
+    pub fn levels(&self) -> Vec<Vec<usize>> {
+        let mut entry_levels: Vec<usize> = Vec::with_capacity(self.ops.len());
+        let mut levels: Vec<Vec<usize>> = Vec::new();
+        for (id, op) in self.ops.iter().enumerate() {
+            let children = op.children();
+            let level = children.iter().map(|&child| entry_levels[child] + 1).max();
+            let level = level.unwrap_or(0);
+            entry_levels.push(level);
+            if level == levels.len() {
+                levels.push(Vec::new());
+            }
+            levels[level].push(id);
+        }
+        levels
+    }
+
+    /// Inserts every entry of `other`, reusing identical entries, and its named outputs, replacing
+    /// outputs with the same names. Returns the id of every entry of `other` in this oplist.
+    pub fn merge(&mut self, other: &OperationList) -> Vec<usize> {
+        let mut ids: Vec<usize> = Vec::with_capacity(other.ops.len());
+        for op in &other.ops {
+            let id = self.insert(op.map_children(|child| ids[child]));
+            ids.push(id);
+        }
+        for (name, &id) in &other.outputs {
+            self.outputs.insert(name.clone(), ids[id]);
+        }
+        ids
+    }
+
+    /// Inserts a node being added, counting whether it was already an entry.
+    fn insert_added(&mut self, expr_ref: ExprPartRef) -> usize {
+        let len = self.ops.len();
+        let id = self.insert(expr_ref);
+        self.added_nodes += 1;
+        if self.ops.len() == len {
+            self.reused_nodes += 1;
+        }
+        id
+    }
+
+    fn insert(&mut self, expr_ref: ExprPartRef) -> usize {
         let ops_set = &mut self.ops_set;
         let ops = &mut self.ops;
 
@@ -48,11 +473,224 @@ impl Default for OperationList {
     }
 }
 
+/// The version of the serialization format, which changes whenever older versions can't read it.
+pub const FORMAT_VERSION: u32 = 1;
+
+/// The version of this crate, from `SIMPLEXP_VERSION_*`.
+fn simplexp_version() -> String {
+    format!(
+        "{}.{}.{}",
+        c_api::SIMPLEXP_VERSION_MAJOR,
+        c_api::SIMPLEXP_VERSION_MINOR,
+        c_api::SIMPLEXP_VERSION_PATCH
+    )
+}
+
+/// Serialized as `{"formatVersion": 1, "simplexpVersion": "(version)", "opSet": {"add": ["a", "b"],
+/// ...}, "ops": [...], "outputs": {"name": (id), ...}}`, with `"metadata"` if there's metadata and
+/// `"hashes": ["(hex)", ...]` if `serialize_hashes` is set.
+///
+/// Deserializing rejects other format versions, and operations whose fields differ from this
+/// version's, so that oplists from incompatible versions can't be misinterpreted. `simplexpVersion`
+/// is informational: any version is accepted, and it is only reported in those errors.
 impl Serialize for OperationList {
     fn serialize<S>(&self, serializer: S) -> Result<S::Ok, S::Error>
     where
         S: Serializer,
     {
-        self.ops.serialize(serializer)
+        #[derive(Serialize)]
+        #[serde(rename_all = "camelCase")]
+        struct Parts<'a> {
+            format_version: u32,
+            simplexp_version: String,
+            op_set: BTreeMap<String, Vec<String>>,
+            #[serde(skip_serializing_if = "Option::is_none")]
+            metadata: Option<&'a serde_json::Value>,
+            ops: &'a [ExprPartRef],
+            outputs: &'a BTreeMap<String, usize>,
+            #[serde(skip_serializing_if = "Option::is_none")]
+            hashes: Option<Vec<String>>,
+        }
+
+        let hashes = self.serialize_hashes.then(|| {
+            let hashes = self.content_hashes().into_iter();
+            hashes.map(|hash| format!("{:016x}", hash)).collect()
+        });
+        Parts {
+            format_version: FORMAT_VERSION,
+            simplexp_version: simplexp_version(),
+            op_set: ExprOpRef::op_set(),
+            metadata: self.metadata.as_ref(),
+            ops: &self.ops,
+            outputs: &self.outputs,
+            hashes,
+        }
+        .serialize(serializer)
+    }
+}
+
+impl<'de> Deserialize<'de> for OperationList {
+    fn deserialize<D>(deserializer: D) -> Result<Self, D::Error>
+    where
+        D: Deserializer<'de>,
+    {
+        // Entries are only parsed once the versions are known to be compatible
+        #[derive(Deserialize)]
+        #[serde(rename_all = "camelCase", deny_unknown_fields)]
+        struct Parts {
+            format_version: u32,
+            simplexp_version: String,
+            op_set: BTreeMap<String, Vec<String>>,
+            #[serde(default)]
+            metadata: Option<serde_json::Value>,
+            ops: Vec<serde_json::Value>,
+            #[serde(default)]
+            outputs: BTreeMap<String, usize>,
+            #[serde(default)]
+            hashes: Option<Vec<String>>,
+        }
+
+        let parts = Parts::deserialize(deserializer)?;
+        if parts.format_version != FORMAT_VERSION {
+            return Err(D::Error::custom(OplistError::UnsupportedFormat {
+                version: parts.format_version,
+                simplexp_version: parts.simplexp_version,
+            }));
+        }
+        let op_set = ExprOpRef::op_set();
+        for (op, fields) in parts.op_set {
+            if op_set
+                .get(&op)
+                .is_some_and(|our_fields| *our_fields != fields)
+            {
+                return Err(D::Error::custom(OplistError::IncompatibleOp {
+                    op,
+                    simplexp_version: parts.simplexp_version,
+                }));
+            }
+        }
+
+        let ops = parts.ops.into_iter().enumerate().map(|(id, op)| {
+            ExprPartRef::from_json(op).map_err(|err| format!("entry {}: {}", id, err))
+        });
+        let ops = ops.collect::<Result<_, _>>().map_err(D::Error::custom)?;
+        let mut oplist = OperationList::from_ops(ops).map_err(D::Error::custom)?;
+        oplist.metadata = parts.metadata;
+        for (name, id) in parts.outputs {
+            oplist.set_output(&name, id).map_err(D::Error::custom)?;
+        }
+        if let Some(hashes) = parts.hashes {
+            if hashes.len() != oplist.ops.len() {
+                return Err(D::Error::invalid_length(
+                    hashes.len(),
+                    &"a content hash for every entry",
+                ));
+            }
+            for (id, (hash, expected)) in
+                hashes.into_iter().zip(oplist.content_hashes()).enumerate()
+            {
+                if u64::from_str_radix(&hash, 16).ok() != Some(expected) {
+                    let err = OplistError::ContentHashMismatch { id, hash };
+                    return Err(D::Error::custom(err));
+                }
+            }
+            oplist.serialize_hashes = true;
+        }
+        Ok(oplist)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::expressions::ExprOp;
+    use crate::patch::Patch;
+
+    fn mul_by(value: f64) -> ExprPart {
+        let x = Arc::new(ExprPart::Operation(ExprOp::Var {
+            name: "x".to_string(),
+        }));
+        let b = Arc::new(ExprPart::FloatLiteral(value));
+        ExprPart::Operation(ExprOp::Mul { a: x, b })
+    }
+
+    #[test]
+    fn signed_zeros_are_distinct() {
+        let mut oplist = OperationList::new();
+        let positive = oplist.add(&mul_by(0.0));
+        let negative = oplist.add(&mul_by(-0.0));
+        assert_ne!(positive, negative);
+        assert_eq!(oplist.ops.len(), 5);
+        match &*oplist.to_expr(negative) {
+            ExprPart::Operation(ExprOp::Mul { b, .. }) => {
+                assert!(matches!(**b, ExprPart::FloatLiteral(v) if v.is_sign_negative()));
+            }
+            expr => panic!("expected a multiplication, found {:?}", expr),
+        }
+
+        let mut arena = Arena::new();
+        assert_ne!(arena.float(0.0).unwrap(), arena.float(-0.0).unwrap());
+
+        let mut old = OperationList::new();
+        old.add(&mul_by(0.0));
+        let mut new = OperationList::new();
+        new.add(&mul_by(-0.0));
+        let patch = Patch::diff(&old, &new);
+        old.apply_patch(&patch).unwrap();
+        assert_eq!(old.ops, new.ops);
+        assert!(!patch.appended.is_empty());
+    }
+
+    #[test]
+    fn envelope_versions() {
+        let mut oplist = OperationList::new();
+        oplist.add(&mul_by(2.0));
+        let envelope = serde_json::to_value(&oplist).unwrap();
+        let deserialize = |changes: serde_json::Value| {
+            let mut envelope = envelope.clone();
+            json_patch(&mut envelope, changes);
+            serde_json::from_value::<OperationList>(envelope).map(|oplist| oplist.ops)
+        };
+
+        let other_version = serde_json::json!({"simplexpVersion": "0.0.1"});
+        assert_eq!(deserialize(other_version).unwrap(), oplist.ops);
+        let err = deserialize(serde_json::json!({"formatVersion": FORMAT_VERSION + 1}));
+        assert!(err.unwrap_err().to_string().contains("format version"));
+        let changes = serde_json::json!({"simplexpVersion": "0.0.1", "opSet": {"mul": ["b", "a"]}});
+        let err = deserialize(changes).unwrap_err().to_string();
+        assert!(err.contains("\"mul\"") && err.contains("0.0.1"), "{}", err);
+    }
+
+    /// Merges `changes` into `value`, object by object.
+    fn json_patch(value: &mut serde_json::Value, changes: serde_json::Value) {
+        match (value, changes) {
+            (serde_json::Value::Object(value), serde_json::Value::Object(changes)) => {
+                for (key, change) in changes {
+                    json_patch(value.entry(key).or_insert(serde_json::Value::Null), change);
+                }
+            }
+            (value, change) => *value = change,
+        }
+    }
+
+    #[test]
+    fn patches_carry_metadata() {
+        let mut old = OperationList::new();
+        old.add(&mul_by(2.0));
+        old.metadata = Some(serde_json::json!({"screen": "main"}));
+        old.serialize_hashes = true;
+        let mut new = OperationList::new();
+        new.add(&mul_by(3.0));
+        new.metadata = Some(serde_json::json!({"screen": "settings"}));
+
+        for (mut from, to) in [(old.clone(), &new), (new.clone(), &old)] {
+            let json = serde_json::to_string(&Patch::diff(&from, to)).unwrap();
+            from.apply_patch(&serde_json::from_str(&json).unwrap())
+                .unwrap();
+            assert_eq!(
+                serde_json::to_value(&from).unwrap(),
+                serde_json::to_value(to).unwrap()
+            );
+        }
     }
 }
@@ -1,168 +1,797 @@
-use crate::expressions::{ExprOp, ExprPart};
+use crate::cost;
+use crate::evaluator::{self, EvalError, Value};
+use crate::expressions::{ExprOp, ExprPart, NanError};
+use crate::interner;
+use crate::rational::Rational;
+use crate::typecheck::{self, ExprType};
+use num_derive::FromPrimitive;
 use std::sync::Arc;
 
+/// How aggressively float expressions may be rewritten.
+#[derive(FromPrimitive, Debug, Clone, Copy, Eq, PartialEq)]
+pub enum FloatMode {
+    /// Rewrites assume floats behave like real numbers, ignoring `-0.0`, infinities and rounding.
+    FastMath = 0,
+    /// Rewrites preserve IEEE-754 results bit for bit, matching the evaluator.
+    Strict = 1,
+}
+
+#[derive(Debug, Clone, Copy)]
+pub struct OptimizerOptions {
+    pub float_mode: FloatMode,
+}
+
+impl OptimizerOptions {
+    fn strict(&self) -> bool {
+        self.float_mode == FloatMode::Strict
+    }
+}
+
+impl Default for OptimizerOptions {
+    fn default() -> Self {
+        OptimizerOptions {
+            float_mode: FloatMode::FastMath,
+        }
+    }
+}
+
+fn literal_value(expr: &ExprPart) -> Result<Value, EvalError> {
+    match expr {
+        ExprPart::IntLiteral(v) => Ok(Value::Int(*v)),
+        ExprPart::FloatLiteral(v) if v.is_nan() => Err(EvalError::NotANumber(NanError)),
+        ExprPart::FloatLiteral(v) => Ok(Value::Float(*v)),
+        ExprPart::StringLiteral(v) => Ok(Value::String(v.clone())),
+        ExprPart::RationalLiteral(v) => Ok(Value::Float(v.to_f64())),
+        ExprPart::Operation(ExprOp::Inf) => Ok(Value::Float(f64::INFINITY)),
+        ExprPart::Operation(ExprOp::NegInf) => Ok(Value::Float(f64::NEG_INFINITY)),
+        ExprPart::Operation(_) => Err(EvalError::NotConstant),
+    }
+}
+
+/// Folds an operation whose operands are all literals, using the evaluator's semantics.
+/// Operations that fail to evaluate, e.g. produce NaN, are left unfolded.
+fn fold_constant(op: &ExprOp) -> Option<Arc<ExprPart>> {
+    if matches!(op, ExprOp::Inf | ExprOp::NegInf) {
+        return None;
+    }
+
+    let value = evaluator::eval_op(op, &evaluator::Context::new(), literal_value).ok()?;
+
+    // A partially filled template may be the template of another format, so the inserted text
+    // must not be able to form new placeholders
+    if let (ExprOp::Format { a, .. }, Value::String(formatted)) = (op, &value) {
+        let inserted = evaluator::to_str(literal_value(a).ok()?);
+        if formatted.contains("{}") && (inserted.is_empty() || inserted.contains(['{', '}'])) {
+            return None;
+        }
+    }
+
+    Some(Arc::new(value.into()))
+}
+
+fn literal_f64(expr: &ExprPart) -> Option<f64> {
+    match expr {
+        ExprPart::IntLiteral(v) => Some(*v as f64),
+        ExprPart::FloatLiteral(v) => Some(*v),
+        ExprPart::RationalLiteral(v) => Some(v.to_f64()),
+        _ => None,
+    }
+}
+
+fn literal_rational(expr: &ExprPart) -> Option<Rational> {
+    match expr {
+        ExprPart::IntLiteral(v) => Some(Rational::from_int(*v)),
+        ExprPart::RationalLiteral(v) => Some(*v),
+        _ => None,
+    }
+}
+
+/// Folds int division, and arithmetic on its results, into exact fractions instead of floats.
+fn fold_rational(op: &ExprOp) -> Option<Arc<ExprPart>> {
+    let is_rational = |expr: &ExprPart| matches!(expr, ExprPart::RationalLiteral(_));
+    let value = match op {
+        ExprOp::Div { a, b } => literal_rational(a)?.checked_div(literal_rational(b)?)?,
+        ExprOp::Add { a, b } if is_rational(a) || is_rational(b) => {
+            literal_rational(a)?.checked_add(literal_rational(b)?)?
+        }
+        ExprOp::Mul { a, b } if is_rational(a) || is_rational(b) => {
+            literal_rational(a)?.checked_mul(literal_rational(b)?)?
+        }
+        ExprOp::Neg { a } if is_rational(a) => literal_rational(a)?.checked_neg()?,
+        _ => return None,
+    };
+    Some(Arc::new(value.to_literal()))
+}
+
+fn is_literal_number(expr: &ExprPart) -> bool {
+    literal_f64(expr).is_some()
+}
+
+/// `(x * a) * b ≡ x * (a*b)`, for literals `a` and `b` on either side.
+/// In strict mode this is only valid for ints, since floats round after each step, and only if `b`
+/// isn't 0 or -1, since otherwise `x * a` may overflow when `x * (a*b)` doesn't.
+fn reassociate_mul(
+    a: &Arc<ExprPart>,
+    b: &Arc<ExprPart>,
+    options: &OptimizerOptions,
+) -> Option<Arc<ExprPart>> {
+    let (inner, outer) = match (&**a, &**b) {
+        (ExprPart::Operation(ExprOp::Mul { a: x, b: y }), _) if is_literal_number(b) => ((x, y), b),
+        (_, ExprPart::Operation(ExprOp::Mul { a: x, b: y })) if is_literal_number(a) => ((x, y), a),
+        (_, _) => return None,
+    };
+    let (x, literal) = match inner {
+        (x, y) if is_literal_number(y) => (x, y),
+        (y, x) if is_literal_number(y) => (x, y),
+        (_, _) => return None,
+    };
+    let all_ints = [&**x, &**literal, &**outer]
+        .iter()
+        .all(|v| typecheck::infer(v).is_int());
+    let preserves_overflow = !matches!(**outer, ExprPart::IntLiteral(0 | -1));
+    if options.strict() && !(all_ints && preserves_overflow) {
+        return None;
+    }
+
+    let product = optimize_with(
+        Arc::new(ExprPart::Operation(ExprOp::Mul {
+            a: literal.clone(),
+            b: outer.clone(),
+        })),
+        options,
+    );
+    if !is_literal_number(&product) {
+        return None;
+    }
+    Some(optimize_with(
+        Arc::new(ExprPart::Operation(ExprOp::Mul {
+            a: x.clone(),
+            b: product,
+        })),
+        options,
+    ))
+}
+
+/// `1 / c`, if multiplying by it is a valid replacement for dividing by `c`.
+fn reciprocal(c: f64, strict: bool) -> Option<f64> {
+    let r = 1.0 / c;
+    if !c.is_normal() || !r.is_normal() {
+        return None;
+    }
+    // Powers of two have an all-zero mantissa, so their reciprocal is exact
+    let exact = c.to_bits() & ((1 << 52) - 1) == 0;
+    if strict && !exact {
+        return None;
+    }
+    Some(r)
+}
+
+fn pow(base: &Arc<ExprPart>, exp: i64) -> Arc<ExprPart> {
+    Arc::new(ExprPart::Operation(ExprOp::Pow {
+        a: base.clone(),
+        b: Arc::new(ExprPart::IntLiteral(exp)),
+    }))
+}
+
+/// Splits `x ** n` into `(x, n)`, anything else is `(x, 1)`.
+fn as_power(expr: &Arc<ExprPart>) -> (&Arc<ExprPart>, i64) {
+    match &**expr {
+        ExprPart::Operation(ExprOp::Pow { a, b }) => match &**b {
+            ExprPart::IntLiteral(n) => (a, *n),
+            _ => (expr, 1),
+        },
+        _ => (expr, 1),
+    }
+}
+
+/// The largest exponent that `x ** n` may be expanded into multiplications for.
+const MAX_MUL_CHAIN_EXP: i64 = 16;
+
+/// Builds `x ** n` out of multiplications, reusing squares.
+fn mul_chain(x: &Arc<ExprPart>, n: u32) -> Arc<ExprPart> {
+    if n == 1 {
+        return x.clone();
+    }
+    let half = mul_chain(x, n / 2);
+    let square = Arc::new(ExprPart::Operation(ExprOp::Mul {
+        a: half.clone(),
+        b: half,
+    }));
+    if n % 2 == 1 {
+        Arc::new(ExprPart::Operation(ExprOp::Mul {
+            a: square,
+            b: x.clone(),
+        }))
+    } else {
+        square
+    }
+}
+
+/// Splits a product of identical factors, e.g. `x * x ** 2 * x`, into `(x, 4)`. Only looks at up
+/// to `2 * MAX_MUL_CHAIN_EXP` nodes, so that long products of distinct factors stay cheap.
+fn repeated_factor(expr: &Arc<ExprPart>) -> Option<(&Arc<ExprPart>, i64)> {
+    let mut factor: Option<&Arc<ExprPart>> = None;
+    let mut exp: i64 = 0;
+    let mut stack = vec![expr];
+    for _ in 0..2 * MAX_MUL_CHAIN_EXP {
+        let Some(node) = stack.pop() else {
+            return factor.map(|factor| (factor, exp));
+        };
+        if let ExprPart::Operation(ExprOp::Mul { a, b }) = &**node {
+            stack.extend([b, a]);
+            continue;
+        }
+        let (base, n) = as_power(node);
+        if n < 1 || factor.is_some_and(|factor| !same(factor, base)) {
+            return None;
+        }
+        factor = Some(base);
+        exp = exp.checked_add(n)?;
+    }
+    None
+}
+
+/// `x * x * ... ≡ x ** n` for `expr ≡ a * b`, if the power or a chain reusing squares is cheaper,
+/// otherwise `(x * a) * b ≡ x * (a*b)`.
+/// In strict mode the power is only valid for ints, since floats round after each step.
+fn rewrite_product(
+    expr: &Arc<ExprPart>,
+    a: &Arc<ExprPart>,
+    b: &Arc<ExprPart>,
+    options: &OptimizerOptions,
+) -> Option<Arc<ExprPart>> {
+    if let Some((x, n)) = repeated_factor(expr) {
+        if !options.strict() || typecheck::infer(x).is_int() {
+            let power = optimize_with(pow(x, n), options);
+            if cost::cost(&power) < cost::cost(expr) {
+                return Some(power);
+            }
+        }
+    }
+    reassociate_mul(a, b, options)
+}
+
+fn is_number(expr: &ExprPart) -> bool {
+    let ty = typecheck::infer(expr);
+    ty.is_int() || ty.is_float()
+}
+
+/// Whether evaluating the expression can't fail, e.g. by producing NaN, so that in strict mode it
+/// can be dropped without hiding an error. Only numeric literals qualify: operations on literals
+/// are folded unless they fail, and variables may be unbound or strings.
+fn cannot_fail(expr: &ExprPart) -> bool {
+    match expr {
+        ExprPart::IntLiteral(_) | ExprPart::RationalLiteral(_) => true,
+        ExprPart::FloatLiteral(v) => !v.is_nan(),
+        ExprPart::Operation(ExprOp::Inf | ExprOp::NegInf) => true,
+        _ => false,
+    }
+}
+
+fn is_positive(expr: &ExprPart) -> bool {
+    literal_f64(expr).is_some_and(|v| v > 0.0)
+}
+
+/// Every way to split `expr` into `(term, rest)` such that `expr ≡ term * rest`, or `term + rest` if
+/// `mul` is false. Anything else is split into itself and the identity.
+fn split_terms(expr: &Arc<ExprPart>, mul: bool) -> Vec<(&Arc<ExprPart>, Arc<ExprPart>)> {
+    match &**expr {
+        ExprPart::Operation(ExprOp::Mul { a, b }) if mul => vec![(a, b.clone()), (b, a.clone())],
+        ExprPart::Operation(ExprOp::Add { a, b }) if !mul => vec![(a, b.clone()), (b, a.clone())],
+        _ => vec![(expr, Arc::new(ExprPart::IntLiteral(mul as i64)))],
+    }
+}
+
+/// Whether `a` and `b` have a term in common that `factor_out` may pull out, which is much cheaper
+/// to check than trying to factor them.
+fn shares_term(a: &ExprPart, b: &ExprPart, mul: bool) -> bool {
+    fn terms(expr: &ExprPart, mul: bool) -> Vec<&ExprPart> {
+        match expr {
+            ExprPart::Operation(ExprOp::Mul { a, b }) if mul => vec![a, b],
+            ExprPart::Operation(ExprOp::Add { a, b }) if !mul => vec![a, b],
+            _ => vec![expr],
+        }
+    }
+    let (a_terms, b_terms) = (terms(a, mul), terms(b, mul));
+    if a_terms.len() == 1 && b_terms.len() == 1 {
+        return false;
+    }
+    a_terms
+        .iter()
+        .any(|a_term| b_terms.iter().any(|b_term| same(a_term, b_term)))
+}
+
+/// Pulls a term shared by `a` and `b` out of `expr ≡ outer(a, b)`, e.g. `a*x + a*y ≡ a*(x+y)`.
+/// The terms are products if `mul` is true, or sums otherwise, and only the shared terms accepted
+/// by `valid_term` are pulled out. Returns `None` unless the result is cheaper.
+fn factor_out(
+    expr: &Arc<ExprPart>,
+    a: &Arc<ExprPart>,
+    b: &Arc<ExprPart>,
+    outer: fn(Arc<ExprPart>, Arc<ExprPart>) -> ExprOp,
+    mul: bool,
+    valid_term: fn(&ExprPart) -> bool,
+    options: &OptimizerOptions,
+) -> Option<Arc<ExprPart>> {
+    let (a_terms, b_terms) = (split_terms(a, mul), split_terms(b, mul));
+    if a_terms.len() == 1 && b_terms.len() == 1 {
+        return None;
+    }
+
+    for (a_term, a_rest) in &a_terms {
+        for (b_term, b_rest) in &b_terms {
+            if !same(a_term, b_term) || !valid_term(a_term) {
+                continue;
+            }
+            let rest = optimize_with(
+                Arc::new(ExprPart::Operation(outer(a_rest.clone(), b_rest.clone()))),
+                options,
+            );
+            let factored = Arc::new(ExprPart::Operation(if mul {
+                ExprOp::Mul {
+                    a: (*a_term).clone(),
+                    b: rest,
+                }
+            } else {
+                ExprOp::Add {
+                    a: (*a_term).clone(),
+                    b: rest,
+                }
+            }));
+            let factored = optimize_with(factored, options);
+            if cost::cost(&factored) < cost::cost(expr) {
+                return Some(factored);
+            }
+        }
+    }
+    None
+}
+
+/// Whether the expression may be a string, ill-typed expressions are reported by the typechecker.
+fn is_string(expr: &ExprPart) -> bool {
+    matches!(typecheck::infer(expr), ExprType::String | ExprType::Any)
+}
+
+fn is_zero(expr: &ExprPart) -> bool {
+    match expr {
+        ExprPart::IntLiteral(v) => *v == 0,
+        ExprPart::FloatLiteral(v) => *v == 0.0,
+        _ => false,
+    }
+}
+
 pub fn optimize(expr: Arc<ExprPart>) -> Arc<ExprPart> {
+    optimize_with(expr, &OptimizerOptions::default())
+}
+
+/// Whether two interned expressions are equal, which is exactly when they are the same node.
+fn same(a: &ExprPart, b: &ExprPart) -> bool {
+    std::ptr::eq(a, b)
+}
+
+/// Optimizes the expression, the result is interned.
+pub fn optimize_with(expr: Arc<ExprPart>, options: &OptimizerOptions) -> Arc<ExprPart> {
+    interner::intern(rewrite(interner::intern(expr), options))
+}
+
+fn rewrite(expr: Arc<ExprPart>, options: &OptimizerOptions) -> Arc<ExprPart> {
+    let strict = options.strict();
+    if let ExprPart::Operation(op) = &*expr {
+        // Optimization: a + b ≡ (a+b), and so on for every operation
+        // In fast-math mode, int division and its results are kept as exact fractions
+        let folded = if strict { None } else { fold_rational(op) };
+        if let Some(folded) = folded.or_else(|| fold_constant(op)) {
+            return folded;
+        }
+    }
+
     match &*expr {
         ExprPart::Operation(op) => match op {
             ExprOp::Var { .. } => expr,
             ExprOp::Add { a, b } => match (&**a, &**b) {
                 // Optimization: x + -x ≡ 0
-                (a, ExprPart::Operation(ExprOp::Neg { a: b })) if a.eq(b) => {
-                    Arc::new(ExprPart::IntLiteral(0))
+                // Never valid in strict mode, since -x overflows for the smallest int and
+                // inf + -inf ≡ NaN
+                // Floats and expressions that may be floats produce 0.0
+                (a, ExprPart::Operation(ExprOp::Neg { a: b })) if same(a, b) && !strict => {
+                    if typecheck::infer(a).is_int() {
+                        Arc::new(ExprPart::IntLiteral(0))
+                    } else {
+                        Arc::new(ExprPart::FloatLiteral(0.0))
+                    }
                 }
 
                 // Optimization: x + 0 ≡ x
-                (_, ExprPart::IntLiteral(0)) => a.clone(),
-                (ExprPart::IntLiteral(0), _) => b.clone(),
-                (_, ExprPart::FloatLiteral(v)) if v.eq(&0.0) => a.clone(),
-                (ExprPart::FloatLiteral(v), _) if v.eq(&0.0) => b.clone(),
-
-                // Optimization: a + b ≡ (a+b)
-                (ExprPart::IntLiteral(a), ExprPart::IntLiteral(b)) => {
-                    Arc::new(ExprPart::IntLiteral(a + b))
+                // In strict mode this is only valid for ints, since -0.0 + 0 ≡ 0.0
+                (x, ExprPart::IntLiteral(0)) if !strict || typecheck::infer(x).is_int() => {
+                    a.clone()
                 }
-                (ExprPart::FloatLiteral(a), ExprPart::IntLiteral(b)) => {
-                    Arc::new(ExprPart::FloatLiteral(a + *b as f64))
+                (ExprPart::IntLiteral(0), x) if !strict || typecheck::infer(x).is_int() => {
+                    b.clone()
                 }
-                (ExprPart::IntLiteral(a), ExprPart::FloatLiteral(b)) => {
-                    Arc::new(ExprPart::FloatLiteral(*a as f64 + b))
+                // In strict mode this is only valid for floats and -0.0, since ints would become floats
+                (x, ExprPart::FloatLiteral(v))
+                    if v.eq(&0.0)
+                        && (!strict
+                            || (v.is_sign_negative() && typecheck::infer(x).is_float())) =>
+                {
+                    a.clone()
                 }
-                (ExprPart::FloatLiteral(a), ExprPart::FloatLiteral(b)) => {
-                    Arc::new(ExprPart::FloatLiteral(a + b))
+                (ExprPart::FloatLiteral(v), x)
+                    if v.eq(&0.0)
+                        && (!strict
+                            || (v.is_sign_negative() && typecheck::infer(x).is_float())) =>
+                {
+                    b.clone()
                 }
 
                 // Optimization: (x + a) + b ≡ x + (a+b)
+                // In strict mode this is only valid for ints, since floats round after each step,
+                // and only if a and b don't have opposite signs, since otherwise x + a may overflow
+                // when x + (a+b) doesn't
                 (
                     ExprPart::Operation(ExprOp::Add { a: left, b: right }),
                     ExprPart::IntLiteral(b),
                 ) => match &**right {
-                    ExprPart::IntLiteral(v) => {
-                        optimize(Arc::new(ExprPart::Operation(ExprOp::Add {
-                            a: left.clone(),
-                            b: Arc::new(ExprPart::IntLiteral(v + b)),
-                        })))
+                    ExprPart::IntLiteral(v)
+                        if !strict
+                            || (typecheck::infer(left).is_int()
+                                && v.signum() * b.signum() >= 0) =>
+                    {
+                        match v.checked_add(*b) {
+                            Some(sum) => optimize_with(
+                                Arc::new(ExprPart::Operation(ExprOp::Add {
+                                    a: left.clone(),
+                                    b: Arc::new(ExprPart::IntLiteral(sum)),
+                                })),
+                                options,
+                            ),
+                            None => expr,
+                        }
                     }
-                    ExprPart::FloatLiteral(v) => {
-                        optimize(Arc::new(ExprPart::Operation(ExprOp::Add {
+                    ExprPart::FloatLiteral(v) if !strict => optimize_with(
+                        Arc::new(ExprPart::Operation(ExprOp::Add {
                             a: left.clone(),
                             b: Arc::new(ExprPart::FloatLiteral(v + *b as f64)),
-                        })))
-                    }
+                        })),
+                        options,
+                    ),
                     _ => expr,
                 },
                 (
                     ExprPart::Operation(ExprOp::Add { a: left, b: right }),
                     ExprPart::FloatLiteral(b),
-                ) => match &**right {
-                    ExprPart::IntLiteral(v) => {
-                        optimize(Arc::new(ExprPart::Operation(ExprOp::Add {
+                ) if !strict => match &**right {
+                    ExprPart::IntLiteral(v) => optimize_with(
+                        Arc::new(ExprPart::Operation(ExprOp::Add {
                             a: left.clone(),
                             b: Arc::new(ExprPart::FloatLiteral(*v as f64 + b)),
-                        })))
-                    }
-                    ExprPart::FloatLiteral(v) => {
-                        optimize(Arc::new(ExprPart::Operation(ExprOp::Add {
+                        })),
+                        options,
+                    ),
+                    ExprPart::FloatLiteral(v) => optimize_with(
+                        Arc::new(ExprPart::Operation(ExprOp::Add {
                             a: left.clone(),
                             b: Arc::new(ExprPart::FloatLiteral(v + b)),
-                        })))
-                    }
+                        })),
+                        options,
+                    ),
                     _ => expr,
                 },
 
+                // Optimization: (x + r) + b ≡ x + (r+b), for exact fractions
+                (ExprPart::Operation(ExprOp::Add { a: left, b: right }), b_literal)
+                    if !strict
+                        && is_literal_number(right)
+                        && is_literal_number(b_literal)
+                        && (matches!(**right, ExprPart::RationalLiteral(_))
+                            || matches!(b_literal, ExprPart::RationalLiteral(_))) =>
+                {
+                    let sum = optimize_with(
+                        Arc::new(ExprPart::Operation(ExprOp::Add {
+                            a: right.clone(),
+                            b: b.clone(),
+                        })),
+                        options,
+                    );
+                    if is_literal_number(&sum) {
+                        optimize_with(
+                            Arc::new(ExprPart::Operation(ExprOp::Add {
+                                a: left.clone(),
+                                b: sum,
+                            })),
+                            options,
+                        )
+                    } else {
+                        expr
+                    }
+                }
+
                 // Optimization: a + x ≡ x + a
                 // This activates the rest of the optimization rules above
-                (ExprPart::IntLiteral(_), ExprPart::Operation(_)) => {
-                    optimize(Arc::new(ExprPart::Operation(ExprOp::Add {
+                (
+                    ExprPart::IntLiteral(_) | ExprPart::RationalLiteral(_),
+                    ExprPart::Operation(_),
+                ) => optimize_with(
+                    Arc::new(ExprPart::Operation(ExprOp::Add {
                         a: b.clone(),
                         b: a.clone(),
-                    })))
-                }
+                    })),
+                    options,
+                ),
 
                 // Optimization: x + inf ≡ inf
-                (_, ExprPart::Operation(ExprOp::Inf)) => Arc::new(ExprPart::Operation(ExprOp::Inf)),
-                (ExprPart::Operation(ExprOp::Inf), _) => Arc::new(ExprPart::Operation(ExprOp::Inf)),
+                // Never valid in strict mode, since -inf + inf ≡ NaN
+                (x, ExprPart::Operation(ExprOp::Inf)) | (ExprPart::Operation(ExprOp::Inf), x)
+                    if !strict && !matches!(x, ExprPart::Operation(ExprOp::NegInf)) =>
+                {
+                    Arc::new(ExprPart::Operation(ExprOp::Inf))
+                }
+                (x, ExprPart::Operation(ExprOp::NegInf))
+                | (ExprPart::Operation(ExprOp::NegInf), x)
+                    if !strict && !matches!(x, ExprPart::Operation(ExprOp::Inf)) =>
+                {
+                    Arc::new(ExprPart::Operation(ExprOp::NegInf))
+                }
+
+                // Optimization: a*x + a*y ≡ a*(x+y), if it is cheaper
+                // Never valid in strict mode, since floats round after each step, and ints may
+                // overflow in one form but not the other
+                (_, _) if !strict && shares_term(a, b, true) => factor_out(
+                    &expr,
+                    a,
+                    b,
+                    |a, b| ExprOp::Add { a, b },
+                    true,
+                    |_| true,
+                    options,
+                )
+                .unwrap_or(expr),
 
                 (_, _) => expr,
             },
             ExprOp::Mul { a, b } => match (&**a, &**b) {
                 // Optimization: x * 1 ≡ x
-                (_, ExprPart::IntLiteral(1)) => a.clone(),
-                (_, ExprPart::FloatLiteral(v)) if v.eq(&1.0) => a.clone(),
+                // In strict mode this is only valid for numbers, and for 1.0 only for floats
+                (x, ExprPart::IntLiteral(1))
+                    if !strict
+                        || typecheck::infer(x).is_int()
+                        || typecheck::infer(x).is_float() =>
+                {
+                    a.clone()
+                }
+                (x, ExprPart::FloatLiteral(v))
+                    if v.eq(&1.0) && (!strict || typecheck::infer(x).is_float()) =>
+                {
+                    a.clone()
+                }
 
-                (_, _) => expr,
+                // Optimization: x * x ** n ≡ x ** (n+1), x ** n * x ** m ≡ x ** (n+m)
+                // In strict mode this is only valid for ints, since floats round after each step
+                (ExprPart::Operation(ExprOp::Pow { .. }), _)
+                | (_, ExprPart::Operation(ExprOp::Pow { .. })) => {
+                    let ((a_base, a_exp), (b_base, b_exp)) = (as_power(a), as_power(b));
+                    let valid = same(a_base, b_base)
+                        && (!strict
+                            || (typecheck::infer(a_base).is_int() && a_exp >= 0 && b_exp >= 0));
+                    match a_exp.checked_add(b_exp) {
+                        Some(exp) if valid => optimize_with(pow(a_base, exp), options),
+                        _ => rewrite_product(&expr, a, b, options).unwrap_or(expr),
+                    }
+                }
+
+                // Optimization: x * x * ... ≡ x ** n, otherwise (x * a) * b ≡ x * (a*b)
+                (_, _) => rewrite_product(&expr, a, b, options).unwrap_or(expr),
             },
             ExprOp::Div { a, b } => match (&**a, &**b) {
                 // Optimization: x / 1 ≡ x
-                (_, ExprPart::IntLiteral(1)) => a.clone(),
-                (_, ExprPart::FloatLiteral(v)) if v.eq(&1.0) => a.clone(),
+                // In strict mode this is only valid for floats, since division produces floats
+                (x, ExprPart::IntLiteral(1)) if !strict || typecheck::infer(x).is_float() => {
+                    a.clone()
+                }
+                (x, ExprPart::FloatLiteral(v))
+                    if v.eq(&1.0) && (!strict || typecheck::infer(x).is_float()) =>
+                {
+                    a.clone()
+                }
 
                 // Optimization: 0 / x ≡ 0
-                (ExprPart::IntLiteral(0), _) => Arc::new(ExprPart::IntLiteral(0)),
-                (ExprPart::FloatLiteral(v), _) if v.eq(&0.0) => Arc::new(ExprPart::IntLiteral(0)),
+                // Never valid in strict mode, since 0 / 0 ≡ NaN and 0 / -x ≡ -0.0
+                (ExprPart::IntLiteral(0), x) if !strict && !is_zero(x) => {
+                    Arc::new(ExprPart::FloatLiteral(0.0))
+                }
+                (ExprPart::FloatLiteral(v), x) if v.eq(&0.0) && !strict && !is_zero(x) => {
+                    Arc::new(ExprPart::FloatLiteral(0.0))
+                }
 
                 // Optimization: (x + x) / 2 ≡ x
-                (ExprPart::Operation(ExprOp::Add { a, b }), ExprPart::IntLiteral(2)) if a.eq(b) => {
+                // Never valid in strict mode, since x + x may overflow
+                (ExprPart::Operation(ExprOp::Add { a, b }), ExprPart::IntLiteral(2))
+                    if same(a, b) && !strict =>
+                {
                     a.clone()
                 }
 
-                (_, _) => expr,
+                // Optimization: x / c ≡ x * (1/c)
+                // In strict mode this is only valid when 1/c is exact, i.e. c is a power of two
+                // In fast-math mode, 1/c is kept as an exact fraction for ints and fractions
+                (_, c) => {
+                    let exact = literal_rational(c)
+                        .filter(|_| !strict)
+                        .and_then(Rational::recip)
+                        .map(Rational::to_literal);
+                    let r = exact.or_else(|| {
+                        literal_f64(c)
+                            .and_then(|c| reciprocal(c, strict))
+                            .map(ExprPart::FloatLiteral)
+                    });
+                    match r {
+                        Some(r) => optimize_with(
+                            Arc::new(ExprPart::Operation(ExprOp::Mul {
+                                a: a.clone(),
+                                b: Arc::new(r),
+                            })),
+                            options,
+                        ),
+                        None => expr,
+                    }
+                }
             },
             ExprOp::Fdiv { a, b } => match (&**a, &**b) {
-                // XXX: This is technically incorrect
-                // Optimization: x / 1 ≡ x
-                (_, ExprPart::IntLiteral(1)) => a.clone(),
-                (_, ExprPart::FloatLiteral(v)) if v.eq(&1.0) => a.clone(),
+                // Optimization: x // 1 ≡ x (only for ints, floats get floored)
+                (_, ExprPart::IntLiteral(1)) if typecheck::infer(a).is_int() => a.clone(),
 
                 // Optimization: 0 // x ≡ 0
-                (ExprPart::IntLiteral(0), _) => Arc::new(ExprPart::IntLiteral(0)),
-                (ExprPart::FloatLiteral(v), _) if v.eq(&0.0) => Arc::new(ExprPart::IntLiteral(0)),
+                // Never valid in strict mode, since 0 // 0 fails
+                // Floats and expressions that may be floats produce 0.0
+                (ExprPart::IntLiteral(0), x) if !strict && !is_zero(x) => {
+                    if typecheck::infer(x).is_int() {
+                        Arc::new(ExprPart::IntLiteral(0))
+                    } else {
+                        Arc::new(ExprPart::FloatLiteral(0.0))
+                    }
+                }
+                (ExprPart::FloatLiteral(v), x) if v.eq(&0.0) && !strict && !is_zero(x) => {
+                    Arc::new(ExprPart::FloatLiteral(0.0))
+                }
 
                 // Optimization: (x + x) // 2 ≡ x // 1
-                (ExprPart::Operation(ExprOp::Add { a, b }), ExprPart::IntLiteral(2)) if a.eq(b) => {
-                    optimize(Arc::new(ExprPart::Operation(ExprOp::Fdiv {
-                        a: a.clone(),
-                        b: Arc::new(ExprPart::IntLiteral(1)),
-                    })))
+                // Never valid in strict mode, since x + x may overflow, which fails for ints and
+                // produces inf for floats
+                (ExprPart::Operation(ExprOp::Add { a, b }), ExprPart::IntLiteral(2))
+                    if same(a, b) && !strict =>
+                {
+                    optimize_with(
+                        Arc::new(ExprPart::Operation(ExprOp::Fdiv {
+                            a: a.clone(),
+                            b: Arc::new(ExprPart::IntLiteral(1)),
+                        })),
+                        options,
+                    )
                 }
 
                 (_, _) => expr,
             },
             // ExprOp::Mod { a, b } => unimplemented!(),
-            // ExprOp::Pow { a, b } => unimplemented!(),
+            ExprOp::Pow { a, b } => match (&**a, &**b) {
+                // Optimization: x ** 0 ≡ 1
+                // In strict mode the type of x must be known, since floats produce 1.0, and x
+                // must not fail, since NaN ** 0 is an error
+                (x, ExprPart::IntLiteral(0)) if !strict || cannot_fail(x) => {
+                    match typecheck::infer(x) {
+                        ty if ty.is_float() => Arc::new(ExprPart::FloatLiteral(1.0)),
+                        ty if ty.is_int() || !strict => Arc::new(ExprPart::IntLiteral(1)),
+                        _ => expr,
+                    }
+                }
+
+                // Optimization: x ** 1 ≡ x
+                // In strict mode this is only valid for numbers, and for 1.0 only for floats
+                (x, ExprPart::IntLiteral(1))
+                    if !strict
+                        || typecheck::infer(x).is_int()
+                        || typecheck::infer(x).is_float() =>
+                {
+                    a.clone()
+                }
+                (x, ExprPart::FloatLiteral(v))
+                    if v.eq(&1.0) && (!strict || typecheck::infer(x).is_float()) =>
+                {
+                    a.clone()
+                }
+
+                // Optimization: (x ** n) ** m ≡ x ** (n*m)
+                // In strict mode this is only valid for ints, since floats round after each step,
+                // and only for m > 0, since x ** n may overflow when x ** 0 doesn't
+                (
+                    ExprPart::Operation(ExprOp::Pow {
+                        a: base,
+                        b: inner_exp,
+                    }),
+                    ExprPart::IntLiteral(m),
+                ) => match &**inner_exp {
+                    ExprPart::IntLiteral(n)
+                        if !strict || (typecheck::infer(base).is_int() && *n >= 0 && *m > 0) =>
+                    {
+                        match n.checked_mul(*m) {
+                            Some(exp) => optimize_with(pow(base, exp), options),
+                            None => expr,
+                        }
+                    }
+                    _ => expr,
+                },
+
+                // Optimization: x ** n ≡ x * x * ..., if the multiplications are cheaper
+                // In strict mode this is only valid for ints, since floats round after each step
+                (x, ExprPart::IntLiteral(n))
+                    if (2..=MAX_MUL_CHAIN_EXP).contains(n)
+                        && (!strict || typecheck::infer(x).is_int()) =>
+                {
+                    let chain = mul_chain(a, *n as u32);
+                    if cost::cost(&chain) < cost::cost(&expr) {
+                        chain
+                    } else {
+                        expr
+                    }
+                }
+
+                (_, _) => expr,
+            },
             // ExprOp::Eq { a, b } => unimplemented!(),
             // ExprOp::Neq { a, b } => unimplemented!(),
             // ExprOp::Lt { a, b } => unimplemented!(),
-            // ExprOp::Lte { a, b } => unimplemented!(),
+            ExprOp::Lte { a, b } | ExprOp::Gte { b: a, a: b } => match (&**a, &**b) {
+                // Optimization: x <= inf ≡ 1, -inf <= x ≡ 1
+                // Never valid in strict mode, since NaN <= inf ≡ 0
+                (_, ExprPart::Operation(ExprOp::Inf))
+                | (ExprPart::Operation(ExprOp::NegInf), _)
+                    if !strict =>
+                {
+                    Arc::new(ExprPart::IntLiteral(1))
+                }
+
+                (_, _) => expr,
+            },
             // ExprOp::Gt { a, b } => unimplemented!(),
-            // ExprOp::Gte { a, b } => unimplemented!(),
             // ExprOp::BAnd { a, b } => unimplemented!(),
             // ExprOp::BOr { a, b } => unimplemented!(),
             // ExprOp::Not { a } => unimplemented!(),
-            ExprOp::Neg { a } => match &**a {
-                // Optimization: -a ≡ -a
-                ExprPart::IntLiteral(v) => Arc::new(ExprPart::IntLiteral(-*v)),
-                ExprPart::FloatLiteral(v) => Arc::new(ExprPart::FloatLiteral(-*v)),
-
-                _ => expr,
-            },
+            // ExprOp::Neg { a } => unimplemented!(),
             // ExprOp::BInvert { a } => unimplemented!(),
             ExprOp::Min { a, b } => match (&**a, &**b) {
-                // Optimization: min(x, inf) ≡ inf
-                (_, ExprPart::Operation(ExprOp::Inf)) => a.clone(),
-                (ExprPart::Operation(ExprOp::Inf), _) => b.clone(),
-
-                // Optimization: min(x, y) ≡ min(x, y)
-                (ExprPart::IntLiteral(a), ExprPart::IntLiteral(b)) => {
-                    Arc::new(ExprPart::IntLiteral((*a).min(*b)))
+                // Optimization: min(x, inf) ≡ x
+                // In strict mode this is only valid for floats, since ints would become floats
+                (x, ExprPart::Operation(ExprOp::Inf))
+                    if !strict || typecheck::infer(x).is_float() =>
+                {
+                    a.clone()
                 }
-                (ExprPart::FloatLiteral(a), ExprPart::IntLiteral(b)) => {
-                    Arc::new(ExprPart::FloatLiteral((*a).min(*b as f64)))
+                (ExprPart::Operation(ExprOp::Inf), x)
+                    if !strict || typecheck::infer(x).is_float() =>
+                {
+                    b.clone()
                 }
-                (ExprPart::IntLiteral(a), ExprPart::FloatLiteral(b)) => {
-                    Arc::new(ExprPart::FloatLiteral((*a as f64).min(*b)))
+
+                // Optimization: min(x, -inf) ≡ -inf
+                // In strict mode this is only valid if x can't fail, since min(NaN, -inf) is an error
+                (x, ExprPart::Operation(ExprOp::NegInf))
+                | (ExprPart::Operation(ExprOp::NegInf), x)
+                    if !strict || cannot_fail(x) =>
+                {
+                    Arc::new(ExprPart::Operation(ExprOp::NegInf))
                 }
-                (ExprPart::FloatLiteral(a), ExprPart::FloatLiteral(b)) => {
-                    Arc::new(ExprPart::FloatLiteral((*a).min(*b)))
+
+                // Optimization: min(x, x) ≡ x
+                (x, y) if same(x, y) && (!strict || is_number(x)) => a.clone(),
+
+                // Optimization: min(x+a, x+b) ≡ x + min(a, b), min(c*x, c*y) ≡ c * min(x, y) for c > 0
+                // Never valid in strict mode, since min(0.0, -0.0) is unspecified, and ints may
+                // overflow in one form but not the other
+                (_, _) if !strict && (shares_term(a, b, false) || shares_term(a, b, true)) => {
+                    let min = |a, b| ExprOp::Min { a, b };
+                    factor_out(&expr, a, b, min, false, |_| true, options)
+                        .or_else(|| factor_out(&expr, a, b, min, true, is_positive, options))
+                        .unwrap_or(expr)
                 }
 
                 (_, _) => expr,
@@ -171,22 +800,25 @@ pub fn optimize(expr: Arc<ExprPart>) -> Arc<ExprPart> {
                 a: a_expr,
                 b: b_expr,
             } => match (&**a_expr, &**b_expr) {
-                // Optimization: max(x, inf) ≡ x
-                (_, ExprPart::Operation(ExprOp::Inf)) => Arc::new(ExprPart::Operation(ExprOp::Inf)),
-                (ExprPart::Operation(ExprOp::Inf), _) => Arc::new(ExprPart::Operation(ExprOp::Inf)),
-
-                // Optimization: max(x, y) ≡ max(x, y)
-                (ExprPart::IntLiteral(a), ExprPart::IntLiteral(b)) => {
-                    Arc::new(ExprPart::IntLiteral((*a).max(*b)))
-                }
-                (ExprPart::FloatLiteral(a), ExprPart::IntLiteral(b)) => {
-                    Arc::new(ExprPart::FloatLiteral((*a).max(*b as f64)))
+                // Optimization: max(x, inf) ≡ inf
+                // In strict mode this is only valid if x can't fail, since max(NaN, inf) is an error
+                (x, ExprPart::Operation(ExprOp::Inf)) | (ExprPart::Operation(ExprOp::Inf), x)
+                    if !strict || cannot_fail(x) =>
+                {
+                    Arc::new(ExprPart::Operation(ExprOp::Inf))
                 }
-                (ExprPart::IntLiteral(a), ExprPart::FloatLiteral(b)) => {
-                    Arc::new(ExprPart::FloatLiteral((*a as f64).max(*b)))
+
+                // Optimization: max(x, -inf) ≡ x
+                // In strict mode this is only valid for floats, since ints would become floats
+                (x, ExprPart::Operation(ExprOp::NegInf))
+                    if !strict || typecheck::infer(x).is_float() =>
+                {
+                    a_expr.clone()
                 }
-                (ExprPart::FloatLiteral(a), ExprPart::FloatLiteral(b)) => {
-                    Arc::new(ExprPart::FloatLiteral((*a).max(*b)))
+                (ExprPart::Operation(ExprOp::NegInf), x)
+                    if !strict || typecheck::infer(x).is_float() =>
+                {
+                    b_expr.clone()
                 }
 
                 // Optimization: max(-c, measureText(...)) ≡ measureText(...)
@@ -240,6 +872,25 @@ pub fn optimize(expr: Arc<ExprPart>) -> Arc<ExprPart> {
                     b_expr.clone()
                 }
 
+                // Optimization: max(x, x) ≡ x
+                (x, y) if same(x, y) && (!strict || is_number(x)) => a_expr.clone(),
+
+                // Optimization: max(x+a, x+b) ≡ x + max(a, b), max(c*x, c*y) ≡ c * max(x, y) for c > 0
+                // Never valid in strict mode, since max(0.0, -0.0) is unspecified, and ints may
+                // overflow in one form but not the other
+                (_, _)
+                    if !strict
+                        && (shares_term(a_expr, b_expr, false)
+                            || shares_term(a_expr, b_expr, true)) =>
+                {
+                    let max = |a, b| ExprOp::Max { a, b };
+                    factor_out(&expr, a_expr, b_expr, max, false, |_| true, options)
+                        .or_else(|| {
+                            factor_out(&expr, a_expr, b_expr, max, true, is_positive, options)
+                        })
+                        .unwrap_or(expr)
+                }
+
                 (_, _) => expr,
             },
             // ExprOp::Abs { a } => unimplemented!(),
@@ -247,8 +898,242 @@ pub fn optimize(expr: Arc<ExprPart>) -> Arc<ExprPart> {
             // ExprOp::ToStr { a } => unimplemented!(),
             // ExprOp::MeasureTextX { text, font_size } => unimplemented!(),
             // ExprOp::MeasureTextY { text, font_size } => unimplemented!(),
+            ExprOp::Concat { a, b } => match (&**a, &**b) {
+                // Optimization: concat(x, "") ≡ x
+                (x, ExprPart::StringLiteral(v)) if v.is_empty() && is_string(x) => a.clone(),
+                (ExprPart::StringLiteral(v), x) if v.is_empty() && is_string(x) => b.clone(),
+
+                // Optimization: concat(concat(x, "a"), "b") ≡ concat(x, "ab")
+                (
+                    ExprPart::Operation(ExprOp::Concat { a: left, b: right }),
+                    ExprPart::StringLiteral(b),
+                ) => match &**right {
+                    ExprPart::StringLiteral(v) => optimize_with(
+                        Arc::new(ExprPart::Operation(ExprOp::Concat {
+                            a: left.clone(),
+                            b: Arc::new(ExprPart::StringLiteral(v.clone() + b)),
+                        })),
+                        options,
+                    ),
+                    _ => expr,
+                },
+
+                (_, _) => expr,
+            },
             _ => expr,
         },
         _ => expr,
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::evaluator::{evaluate, Context};
+
+    /// A xorshift generator, so that failures are reproducible.
+    struct Rng(u64);
+
+    impl Rng {
+        fn below(&mut self, n: usize) -> usize {
+            self.0 ^= self.0 << 13;
+            self.0 ^= self.0 >> 7;
+            self.0 ^= self.0 << 17;
+            (self.0 % n as u64) as usize
+        }
+    }
+
+    fn leaf(rng: &mut Rng) -> ExprPart {
+        match rng.below(10) {
+            0 => ExprPart::Operation(ExprOp::Var {
+                name: "x".to_string(),
+            }),
+            1 => ExprPart::Operation(ExprOp::Var {
+                name: "y".to_string(),
+            }),
+            2 => ExprPart::Operation(ExprOp::Inf),
+            3 => ExprPart::Operation(ExprOp::NegInf),
+            4 => ExprPart::IntLiteral([0, 1, 2, -1, -3, 1 << 62, i64::MAX][rng.below(7)]),
+            _ => ExprPart::FloatLiteral([0.0, -0.0, 1.0, 1.5, -2.0, 1e308][rng.below(6)]),
+        }
+    }
+
+    fn random_expr(rng: &mut Rng, depth: usize) -> Arc<ExprPart> {
+        if depth == 0 || rng.below(4) == 0 {
+            return Arc::new(leaf(rng));
+        }
+        let kind = rng.below(13);
+        let a = random_expr(rng, depth - 1);
+        let b = random_expr(rng, depth - 1);
+        let op = match kind {
+            0 => ExprOp::Add { a, b },
+            1 => ExprOp::Mul { a, b },
+            2 => ExprOp::Div { a, b },
+            3 => ExprOp::Fdiv { a, b },
+            4 => ExprOp::Mod { a, b },
+            5 => ExprOp::Pow { a, b },
+            6 => ExprOp::Neg { a },
+            7 => ExprOp::Min { a, b },
+            8 => ExprOp::Max { a, b },
+            9 => ExprOp::Abs { a },
+            10 => ExprOp::Lt { a, b },
+            11 => ExprOp::Eq { a, b },
+            _ => ExprOp::Add {
+                a: a.clone(),
+                b: Arc::new(ExprPart::Operation(ExprOp::Neg { a })),
+            },
+        };
+        Arc::new(ExprPart::Operation(op))
+    }
+
+    #[test]
+    fn nan_is_never_folded() {
+        let zero = || Arc::new(ExprPart::FloatLiteral(0.0));
+        let inf = || Arc::new(ExprPart::Operation(ExprOp::Inf));
+        let neg_inf = Arc::new(ExprPart::Operation(ExprOp::Neg { a: inf() }));
+        let exprs = [
+            ExprOp::Div {
+                a: zero(),
+                b: zero(),
+            },
+            ExprOp::Add {
+                a: inf(),
+                b: neg_inf,
+            },
+            ExprOp::Mul {
+                a: zero(),
+                b: inf(),
+            },
+        ];
+        for float_mode in [FloatMode::Strict, FloatMode::FastMath] {
+            let options = OptimizerOptions { float_mode };
+            for op in &exprs {
+                let expr = Arc::new(ExprPart::Operation(op.clone()));
+                let optimized = optimize_nodes(&expr, &options);
+                assert!(
+                    matches!(*optimized, ExprPart::Operation(_)),
+                    "{:?} was folded",
+                    expr
+                );
+                assert_eq!(
+                    evaluate(&optimized, &Context::new()),
+                    Err(EvalError::NotANumber(NanError))
+                );
+            }
+        }
+    }
+
+    #[test]
+    fn repeated_factors_become_powers() {
+        let s = Arc::new(ExprPart::Operation(ExprOp::Var {
+            name: "s".to_string(),
+        }));
+        let int = Arc::new(ExprPart::Operation(ExprOp::StrLen { a: s }));
+        let float = Arc::new(ExprPart::Operation(ExprOp::Mul {
+            a: int.clone(),
+            b: Arc::new(ExprPart::FloatLiteral(1.5)),
+        }));
+        let product = |x: &Arc<ExprPart>, n: usize| {
+            let mut product = x.clone();
+            for _ in 1..n {
+                product = Arc::new(ExprPart::Operation(ExprOp::Mul {
+                    a: product,
+                    b: x.clone(),
+                }));
+            }
+            product
+        };
+        let options = OptimizerOptions {
+            float_mode: FloatMode::Strict,
+        };
+        for n in 2..=20 {
+            let expr = product(&int, n);
+            let optimized = optimize_nodes(&expr, &options);
+            let power = optimize_with(pow(&int, n as i64), &options);
+            assert_eq!(
+                cost::cost(&optimized),
+                cost::cost(&power).min(cost::cost(&expr))
+            );
+            // Long strings make the products overflow
+            for text in ["abc", &"a".repeat(1000)] {
+                let ctx = Context::new().with_var("s", Value::String(text.to_string()));
+                assert_eq!(evaluate(&optimized, &ctx), evaluate(&expr, &ctx));
+            }
+
+            let expr = product(&float, n);
+            let optimized = optimize_nodes(&expr, &options);
+            assert_eq!(cost::cost(&optimized), cost::cost(&expr));
+        }
+
+        // (x * x) * x ** 2, whose operands have different bases
+        let expr = Arc::new(ExprPart::Operation(ExprOp::Mul {
+            a: product(&int, 2),
+            b: pow(&int, 2),
+        }));
+        let optimized = optimize_nodes(&expr, &options);
+        let power = optimize_with(pow(&int, 4), &options);
+        assert!(Arc::ptr_eq(&optimized, &power));
+        assert!(cost::cost(&optimized) < cost::cost(&expr));
+    }
+
+    /// Optimizes every node, like building the expression through the C API does.
+    fn optimize_nodes(expr: &Arc<ExprPart>, options: &OptimizerOptions) -> Arc<ExprPart> {
+        let mut part = (**expr).clone();
+        if let ExprPart::Operation(op) = &mut part {
+            for child in op.children_mut() {
+                *child = optimize_nodes(child, options);
+            }
+        }
+        optimize_with(Arc::new(part), options)
+    }
+
+    fn same_result(a: &Result<Value, EvalError>, b: &Result<Value, EvalError>) -> bool {
+        match (a, b) {
+            (Ok(Value::Float(a)), Ok(Value::Float(b))) => a.to_bits() == b.to_bits(),
+            (Ok(a), Ok(b)) => a == b,
+            (Err(_), Err(_)) => true,
+            (_, _) => false,
+        }
+    }
+
+    #[test]
+    fn strict_rewrites_match_evaluator() {
+        let options = OptimizerOptions {
+            float_mode: FloatMode::Strict,
+        };
+        let values = [
+            Value::Int(0),
+            Value::Int(3),
+            Value::Int(-1),
+            Value::Int(1 << 62),
+            Value::Int(i64::MIN),
+            Value::Int(i64::MAX),
+            Value::Float(0.0),
+            Value::Float(-0.0),
+            Value::Float(2.5),
+            Value::Float(f64::INFINITY),
+            Value::Float(f64::NEG_INFINITY),
+        ];
+        let mut rng = Rng(0x2545_f491_4f6c_dd1d);
+        let mut mismatches = Vec::new();
+        for _ in 0..20_000 {
+            let expr = random_expr(&mut rng, 3);
+            let optimized = optimize_nodes(&expr, &options);
+            for _ in 0..4 {
+                let (x, y) = (rng.below(values.len()), rng.below(values.len()));
+                let ctx = Context::new()
+                    .with_var("x", values[x].clone())
+                    .with_var("y", values[y].clone());
+                let expected = evaluate(&expr, &ctx);
+                let actual = evaluate(&optimized, &ctx);
+                if !same_result(&expected, &actual) {
+                    mismatches.push(format!(
+                        "{:?} => {:?} with x = {:?}, y = {:?}: {:?} != {:?}",
+                        expr, optimized, values[x], values[y], expected, actual
+                    ));
+                }
+            }
+        }
+        assert!(mismatches.is_empty(), "{}", mismatches.join("\n"));
+    }
+}
@@ -0,0 +1,153 @@
+use crate::expressions::ExprPartRef;
+use crate::operation_list::{OperationList, OplistError};
+use serde::{Deserialize, Serialize};
+use std::collections::{BTreeMap, HashMap};
+
+/// The changes between two oplists, for sending an updated oplist without resending every entry.
+///
+/// Applying the patch removes entries of the old oplist, renumbering the rest without changing
+/// their order, and then appends entries. Serialized as
+/// `{"removed": [[(start), (end)], ...], "appended": [...], "outputs": {(name): (id) | null},
+/// "metadata": (metadata), "serializeHashes": true}`, where empty fields are omitted.
+#[derive(Serialize, Deserialize, Debug, Clone, Default, PartialEq)]
+#[serde(deny_unknown_fields, rename_all = "camelCase")]
+pub struct Patch {
+    /// Ranges of old ids that are removed, each range after the previous one.
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub removed: Vec<(usize, usize)>,
+    /// Entries appended after the kept ones, whose operands are new ids.
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub appended: Vec<ExprPartRef>,
+    /// The new id of every changed output, or `None` for removed outputs.
+    #[serde(default, skip_serializing_if = "BTreeMap::is_empty")]
+    pub outputs: BTreeMap<String, Option<usize>>,
+    /// The metadata of the new oplist, which replaces the old metadata.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub metadata: Option<serde_json::Value>,
+    /// Whether the new oplist serializes its content hashes.
+    #[serde(default, skip_serializing_if = "std::ops::Not::not")]
+    pub serialize_hashes: bool,
+}
+
+impl Patch {
+    /// Computes a patch that turns `old` into exactly `new`.
+    ///
+    /// The longest prefix of `new` made of old entries, in their old order, is kept, and the rest
+    /// of `new` is appended. Compacting and appending to an oplist keeps the order of its entries,
+    /// so only new entries are appended.
+    pub fn diff(old: &OperationList, new: &OperationList) -> Patch {
+        // The ids of every old entry, in increasing order
+        let mut old_ids: HashMap<&ExprPartRef, Vec<usize>> = HashMap::new();
+        for (id, op) in old.ops.iter().enumerate() {
+            old_ids.entry(op).or_default().push(id);
+        }
+
+        // The old id of every kept entry, which is the prefix of `new` up to `kept.len()`
+        let mut kept: Vec<usize> = Vec::new();
+        for op in &new.ops {
+            if op.children().iter().any(|&child| child >= kept.len()) {
+                break;
+            }
+            let old_op = op.map_children(|child| kept[child]);
+            let last = kept.last().copied();
+            let next = old_ids
+                .get(&old_op)
+                .and_then(|ids| ids.get(ids.partition_point(|&id| Some(id) <= last)));
+            match next {
+                Some(&id) => kept.push(id),
+                None => break,
+            }
+        }
+
+        let mut removed = Vec::new();
+        let mut start = 0;
+        for end in kept.iter().copied().chain(Some(old.ops.len())) {
+            if start < end {
+                removed.push((start, end));
+            }
+            start = end + 1;
+        }
+
+        let mut new_ids: Vec<Option<usize>> = vec![None; old.ops.len()];
+        for (new_id, &old_id) in kept.iter().enumerate() {
+            new_ids[old_id] = Some(new_id);
+        }
+        let mut outputs = BTreeMap::new();
+        for name in old.outputs.keys().chain(new.outputs.keys()) {
+            let carried = old
+                .output(name)
+                .and_then(|id| new_ids.get(id).copied().flatten());
+            // Outputs of removed entries must be changed even if they are removed too
+            if carried.is_none() || carried != new.output(name) {
+                outputs.insert(name.clone(), new.output(name));
+            }
+        }
+
+        Patch {
+            removed,
+            appended: new.ops[kept.len()..].to_vec(),
+            outputs,
+            metadata: new.metadata.clone(),
+            serialize_hashes: new.serialize_hashes,
+        }
+    }
+}
+
+impl OperationList {
+    /// Applies a patch computed by `Patch::diff` from this oplist, which then equals the new one.
+    /// The oplist is left unchanged if the patch doesn't apply.
+    pub fn apply_patch(&mut self, patch: &Patch) -> Result<(), OplistError> {
+        let mut removed = vec![false; self.ops.len()];
+        let mut prev_end = 0;
+        for &(start, end) in &patch.removed {
+            if start < prev_end || start >= end || end > self.ops.len() {
+                return Err(OplistError::InvalidRemoval { start, end });
+            }
+            removed[start..end].fill(true);
+            prev_end = end;
+        }
+
+        let mut new_ids: Vec<Option<usize>> = vec![None; self.ops.len()];
+        let new_id = |new_ids: &[Option<usize>], id: usize| new_ids.get(id).copied().flatten();
+        let mut ops = Vec::with_capacity(self.ops.len() + patch.appended.len());
+        for (id, op) in self.ops.iter().enumerate() {
+            if removed[id] {
+                continue;
+            }
+            let children = op.children();
+            if let Some(&operand) = children.iter().find(|&&c| new_id(&new_ids, c).is_none()) {
+                return Err(OplistError::RemovedOperand { id, operand });
+            }
+            new_ids[id] = Some(ops.len());
+            ops.push(op.map_children(|child| new_ids[child].unwrap()));
+        }
+        ops.extend(patch.appended.iter().cloned());
+
+        let mut oplist = OperationList::from_ops(ops)?;
+        for (name, &id) in &self.outputs {
+            if patch.outputs.contains_key(name) {
+                continue;
+            }
+            match new_id(&new_ids, id) {
+                Some(id) => oplist.set_output(name, id)?,
+                None => {
+                    return Err(OplistError::RemovedOutput {
+                        name: name.clone(),
+                        id,
+                    })
+                }
+            }
+        }
+        for (name, id) in &patch.outputs {
+            if let Some(id) = *id {
+                oplist.set_output(name, id)?;
+            }
+        }
+        oplist.metadata = patch.metadata.clone();
+        oplist.serialize_hashes = patch.serialize_hashes;
+        oplist.added_nodes = self.added_nodes;
+        oplist.reused_nodes = self.reused_nodes;
+        *self = oplist;
+        Ok(())
+    }
+}
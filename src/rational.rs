@@ -0,0 +1,103 @@
+use crate::expressions::ExprPart;
+use serde::Serialize;
+use std::convert::TryFrom;
+use std::fmt::{Debug, Formatter};
+
+/// An exact fraction, produced by dividing ints while optimizing.
+/// It is always reduced, and the denominator is always positive.
+#[derive(Serialize, Clone, Copy, Hash, Eq, PartialEq)]
+#[serde(tag = "type", rename = "rational")]
+pub struct Rational {
+    num: i64,
+    den: i64,
+}
+
+fn gcd(a: i64, b: i64) -> Option<i64> {
+    let (mut a, mut b) = (a.unsigned_abs(), b.unsigned_abs());
+    while b != 0 {
+        let rem = a % b;
+        a = b;
+        b = rem;
+    }
+    i64::try_from(a).ok()
+}
+
+impl Rational {
+    /// Returns `None` if the denominator is zero, or the fraction can't be represented.
+    pub fn new(num: i64, den: i64) -> Option<Rational> {
+        if den == 0 {
+            return None;
+        }
+        let divisor = gcd(num, den)?;
+        let (num, den) = (num.checked_div(divisor)?, den.checked_div(divisor)?);
+        if den < 0 {
+            Some(Rational {
+                num: num.checked_neg()?,
+                den: den.checked_neg()?,
+            })
+        } else {
+            Some(Rational { num, den })
+        }
+    }
+
+    pub fn from_int(value: i64) -> Rational {
+        Rational { num: value, den: 1 }
+    }
+
+    pub fn num(&self) -> i64 {
+        self.num
+    }
+
+    pub fn den(&self) -> i64 {
+        self.den
+    }
+
+    pub fn checked_add(self, other: Rational) -> Option<Rational> {
+        let num = self
+            .num
+            .checked_mul(other.den)?
+            .checked_add(other.num.checked_mul(self.den)?)?;
+        Rational::new(num, self.den.checked_mul(other.den)?)
+    }
+
+    pub fn checked_mul(self, other: Rational) -> Option<Rational> {
+        Rational::new(
+            self.num.checked_mul(other.num)?,
+            self.den.checked_mul(other.den)?,
+        )
+    }
+
+    pub fn checked_div(self, other: Rational) -> Option<Rational> {
+        self.checked_mul(other.recip()?)
+    }
+
+    pub fn checked_neg(self) -> Option<Rational> {
+        Rational::new(self.num.checked_neg()?, self.den)
+    }
+
+    pub fn recip(self) -> Option<Rational> {
+        Rational::new(self.den, self.num)
+    }
+
+    pub fn to_f64(self) -> f64 {
+        self.num as f64 / self.den as f64
+    }
+
+    /// The literal for this value: a float if it is exactly representable as one, since division
+    /// produces floats, or a rational literal otherwise.
+    pub fn to_literal(self) -> ExprPart {
+        const MAX_EXACT_INT: u64 = 1 << f64::MANTISSA_DIGITS;
+        let exact = self.den.count_ones() == 1 && self.num.unsigned_abs() <= MAX_EXACT_INT;
+        if exact {
+            ExprPart::FloatLiteral(self.to_f64())
+        } else {
+            ExprPart::RationalLiteral(self)
+        }
+    }
+}
+
+impl Debug for Rational {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}/{}", self.num, self.den)
+    }
+}
@@ -0,0 +1,79 @@
+use crate::expressions::{ExprOpRef, ExprPartRef};
+use crate::operation_list::OperationList;
+use serde::Serialize;
+use std::collections::{BTreeMap, BTreeSet};
+
+/// Size and shape of an oplist, for tracking how big the oplists of a screen get.
+#[derive(Serialize, Debug, Clone, Default, PartialEq)]
+#[serde(rename_all = "camelCase")]
+pub struct OplistStats {
+    pub entries: usize,
+    /// The number of operations of every type, e.g. `"add"`.
+    pub ops: BTreeMap<String, usize>,
+    pub int_literals: usize,
+    pub float_literals: usize,
+    pub string_literals: usize,
+    pub rational_literals: usize,
+    /// The names of the distinct variables.
+    pub variables: Vec<String>,
+    /// The number of entries on the longest chain of operands, 1 for a literal or a variable.
+    pub max_depth: usize,
+    /// The largest number of times an entry is an operand.
+    pub max_fan_out: usize,
+    /// The number of entries that are an operand more than once.
+    pub shared_entries: usize,
+    /// The number of nodes added by `add`, `add_output` and `add_from_arena`.
+    pub added_nodes: usize,
+    /// The number of added nodes that were already entries.
+    pub reused_nodes: usize,
+    /// The fraction of added nodes that were already entries, 0 if nothing was added.
+    pub dedup_hit_rate: f64,
+}
+
+impl OperationList {
+    pub fn stats(&self) -> OplistStats {
+        let mut stats = OplistStats {
+            entries: self.ops.len(),
+            added_nodes: self.added_nodes,
+            reused_nodes: self.reused_nodes,
+            ..OplistStats::default()
+        };
+        if self.added_nodes > 0 {
+            stats.dedup_hit_rate = self.reused_nodes as f64 / self.added_nodes as f64;
+        }
+
+        let mut variables = BTreeSet::new();
+        let mut depths: Vec<usize> = Vec::with_capacity(self.ops.len());
+        let mut fan_outs = vec![0; self.ops.len()];
+        for op in &self.ops {
+            match op {
+                ExprPartRef::IntLiteral(_) => stats.int_literals += 1,
+                ExprPartRef::FloatLiteral(_) => stats.float_literals += 1,
+                ExprPartRef::StringLiteral(_) => stats.string_literals += 1,
+                ExprPartRef::RationalLiteral(_) => stats.rational_literals += 1,
+                ExprPartRef::Operation(op) => {
+                    *stats.ops.entry(op.type_name()).or_default() += 1;
+                    if let ExprOpRef::Var { name } = op {
+                        variables.insert(name.as_str());
+                    }
+                }
+            }
+
+            // Operands are earlier entries, others are ignored
+            let children = op.children();
+            let operand_depth = children.iter().filter_map(|&child| depths.get(child)).max();
+            depths.push(operand_depth.map_or(1, |depth| depth + 1));
+            for child in children {
+                if let Some(fan_out) = fan_outs.get_mut(child) {
+                    *fan_out += 1;
+                }
+            }
+        }
+
+        stats.variables = variables.into_iter().map(str::to_string).collect();
+        stats.max_depth = depths.into_iter().max().unwrap_or(0);
+        stats.max_fan_out = fan_outs.iter().copied().max().unwrap_or(0);
+        stats.shared_entries = fan_outs.iter().filter(|&&fan_out| fan_out > 1).count();
+        stats
+    }
+}
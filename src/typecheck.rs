@@ -0,0 +1,305 @@
+use crate::expressions::{ExprOp, ExprPart};
+use serde::Serialize;
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex, OnceLock, Weak};
+
+/// The static type of an expression.
+///
+/// Variables and infinities are `Any`: variables are only bound at evaluation time, and infinities
+/// are used as unbounded sentinels for both ints and floats.
+#[derive(Serialize, Debug, Clone, Copy, Eq, PartialEq)]
+#[serde(rename_all = "camelCase")]
+pub enum ExprType {
+    Int,
+    Float,
+    String,
+    /// Result of a comparison, behaves as an int with the value 0 or 1.
+    Bool,
+    Any,
+}
+
+impl ExprType {
+    pub fn is_int(self) -> bool {
+        matches!(self, ExprType::Int | ExprType::Bool)
+    }
+
+    pub fn is_float(self) -> bool {
+        matches!(self, ExprType::Float)
+    }
+
+    fn is_numeric(self) -> bool {
+        !matches!(self, ExprType::String)
+    }
+
+    /// The type of a branch that may evaluate to either `self` or `other`.
+    fn unify(self, other: ExprType) -> ExprType {
+        match (self, other) {
+            (a, b) if a == b => a,
+            (a, b) if a.is_int() && b.is_int() => ExprType::Int,
+            (_, _) => ExprType::Any,
+        }
+    }
+
+    /// The type of an arithmetic operation on `self` and `other`, assuming both are numeric.
+    fn promote(self, other: ExprType) -> ExprType {
+        match (self, other) {
+            (a, b) if a.is_int() && b.is_int() => ExprType::Int,
+            (ExprType::Float, b) | (b, ExprType::Float) if b.is_numeric() && b != ExprType::Any => {
+                ExprType::Float
+            }
+            (_, _) => ExprType::Any,
+        }
+    }
+}
+
+#[derive(Serialize, Debug, Clone)]
+pub struct TypeError {
+    /// The formatted expression that failed to typecheck.
+    pub expr: String,
+    pub message: String,
+}
+
+#[derive(Serialize, Debug, Clone)]
+pub struct TypeCheck {
+    #[serde(rename = "type")]
+    pub ty: ExprType,
+    pub errors: Vec<TypeError>,
+}
+
+/// Assigns a type to every node of the expression, collecting all type errors.
+pub fn typecheck(expr: &ExprPart) -> TypeCheck {
+    let mut errors = Vec::new();
+    let ty = check(expr, Some(&mut errors));
+    TypeCheck { ty, errors }
+}
+
+/// The number of nodes below which dead nodes are never swept from the type cache.
+const MIN_SWEEP_LEN: usize = 1024;
+
+/// Inferred types of nodes by address. Nodes are only weakly referenced, so that the cache doesn't
+/// keep expressions alive, but that keeps their allocation, so their address isn't reused.
+#[derive(Default)]
+struct TypeCache {
+    types: HashMap<usize, (Weak<ExprPart>, ExprType)>,
+    /// The number of nodes left after the last sweep of dead nodes.
+    swept_len: usize,
+}
+
+impl TypeCache {
+    fn get(&self, expr: &ExprPart) -> Option<ExprType> {
+        let (node, ty) = self.types.get(&(expr as *const ExprPart as usize))?;
+        (node.strong_count() > 0).then_some(*ty)
+    }
+
+    fn insert(&mut self, expr: &Arc<ExprPart>, ty: ExprType) {
+        // Dead nodes are swept whenever the cache doubles in size, so sweeping is amortized O(1)
+        if self.types.len() >= (self.swept_len * 2).max(MIN_SWEEP_LEN) {
+            self.types.retain(|_, (node, _)| node.strong_count() > 0);
+            self.swept_len = self.types.len();
+        }
+        let key = Arc::as_ptr(expr) as usize;
+        self.types.insert(key, (Arc::downgrade(expr), ty));
+    }
+}
+
+fn type_cache() -> &'static Mutex<TypeCache> {
+    static CACHE: OnceLock<Mutex<TypeCache>> = OnceLock::new();
+    CACHE.get_or_init(Mutex::default)
+}
+
+/// Infers the type of the expression without collecting errors.
+/// Ill-typed nodes get the type they would have if they were well-typed.
+///
+/// The types of operands are cached, since the optimizer infers the types of the operands of every
+/// node it builds, so only the nodes that weren't inferred before are visited.
+pub fn infer(expr: &ExprPart) -> ExprType {
+    let mut cache = type_cache().lock().unwrap();
+    if let Some(ty) = cache.get(expr) {
+        return ty;
+    }
+
+    let mut types: HashMap<*const ExprPart, ExprType> = HashMap::new();
+    let order = expr.post_order_except(|node| cache.get(node).is_some());
+    for &node in &order {
+        let type_of = |child: &ExprPart| match types.get(&(child as *const ExprPart)) {
+            Some(&ty) => ty,
+            None => cache.get(child).unwrap(),
+        };
+        let ty = check_node(node, type_of, &mut Vec::new());
+        types.insert(node, ty);
+    }
+    // Only operands can be cached, since the expression itself isn't known to be in an `Arc`
+    for node in order {
+        if let ExprPart::Operation(op) = node {
+            for child in op.children() {
+                if let Some(&ty) = types.get(&Arc::as_ptr(child)) {
+                    cache.insert(child, ty);
+                }
+            }
+        }
+    }
+    types[&(expr as *const ExprPart)]
+}
+
+fn check(expr: &ExprPart, mut errors: Option<&mut Vec<TypeError>>) -> ExprType {
+    // Operands are checked before the operations using them, shared ones only once
+    let mut types: HashMap<*const ExprPart, ExprType> = HashMap::new();
+    for node in expr.post_order() {
+        let mut problems = Vec::new();
+        let ty = check_node(
+            node,
+            |child| types[&(child as *const ExprPart)],
+            &mut problems,
+        );
+        if let Some(errors) = errors.as_mut() {
+            for message in problems {
+                errors.push(TypeError {
+                    expr: format!("{:?}", node),
+                    message: message.to_string(),
+                });
+            }
+        }
+        types.insert(node, ty);
+    }
+    types[&(expr as *const ExprPart)]
+}
+
+/// The type of a single node, given the types of its operands.
+fn check_node(
+    expr: &ExprPart,
+    type_of: impl Fn(&ExprPart) -> ExprType,
+    problems: &mut Vec<&'static str>,
+) -> ExprType {
+    let op = match expr {
+        ExprPart::IntLiteral(_) => return ExprType::Int,
+        ExprPart::FloatLiteral(_) | ExprPart::RationalLiteral(_) => return ExprType::Float,
+        ExprPart::StringLiteral(_) => return ExprType::String,
+        ExprPart::Operation(op) => op,
+    };
+
+    match op {
+        ExprOp::Var { .. } | ExprOp::Inf | ExprOp::NegInf => ExprType::Any,
+        ExprOp::Add { a, b }
+        | ExprOp::Mul { a, b }
+        | ExprOp::Mod { a, b }
+        | ExprOp::Min { a, b }
+        | ExprOp::Max { a, b } => {
+            let (a, b) = (type_of(a), type_of(b));
+            if !a.is_numeric() || !b.is_numeric() {
+                problems.push("operands must be numeric");
+            }
+            a.promote(b)
+        }
+        ExprOp::Pow { a, b: b_expr } => {
+            let (a, b) = (type_of(a), type_of(b_expr));
+            if !a.is_numeric() || !b.is_numeric() {
+                problems.push("operands must be numeric");
+            }
+            match (a.promote(b), &**b_expr) {
+                // Negative int exponents produce floats
+                (ExprType::Int, ExprPart::IntLiteral(v)) if *v >= 0 => ExprType::Int,
+                (ExprType::Int, _) => ExprType::Any,
+                (ty, _) => ty,
+            }
+        }
+        ExprOp::Div { a, b } => {
+            let (a, b) = (type_of(a), type_of(b));
+            if !a.is_numeric() || !b.is_numeric() {
+                problems.push("operands must be numeric");
+            }
+            ExprType::Float
+        }
+        ExprOp::Fdiv { a, b } => {
+            let (a, b) = (type_of(a), type_of(b));
+            if !a.is_numeric() || !b.is_numeric() {
+                problems.push("operands must be numeric");
+            }
+            a.promote(b)
+        }
+        ExprOp::Eq { .. } | ExprOp::Neq { .. } => ExprType::Bool,
+        ExprOp::Lt { a, b } | ExprOp::Lte { a, b } | ExprOp::Gt { a, b } | ExprOp::Gte { a, b } => {
+            let (a, b) = (type_of(a), type_of(b));
+            if a.is_numeric() != b.is_numeric() && a != ExprType::Any && b != ExprType::Any {
+                problems.push("cannot compare a string with a number");
+            }
+            ExprType::Bool
+        }
+        ExprOp::BAnd { a, b } | ExprOp::BOr { a, b } => {
+            let (a, b) = (type_of(a), type_of(b));
+            if !matches!(a, ExprType::Int | ExprType::Bool | ExprType::Any)
+                || !matches!(b, ExprType::Int | ExprType::Bool | ExprType::Any)
+            {
+                problems.push("operands must be ints");
+            }
+            match (a, b) {
+                (ExprType::Bool, ExprType::Bool) => ExprType::Bool,
+                (_, _) => ExprType::Int,
+            }
+        }
+        ExprOp::Neg { a } | ExprOp::Abs { a } => {
+            let a = type_of(a);
+            if !a.is_numeric() {
+                problems.push("operand must be numeric");
+            }
+            a.promote(a)
+        }
+        ExprOp::BInvert { a } => {
+            let a = type_of(a);
+            if !matches!(a, ExprType::Int | ExprType::Bool | ExprType::Any) {
+                problems.push("operand must be an int");
+            }
+            ExprType::Int
+        }
+        ExprOp::ToStr { .. } => ExprType::String,
+        ExprOp::MeasureTextX { text, font_size } | ExprOp::MeasureTextY { text, font_size } => {
+            let (text, font_size) = (type_of(text), type_of(font_size));
+            if !matches!(text, ExprType::String | ExprType::Any) {
+                problems.push("text must be a string");
+            }
+            if !font_size.is_numeric() {
+                problems.push("font size must be numeric");
+            }
+            ExprType::Float
+        }
+        ExprOp::If { cond, t, f } => {
+            let (cond, t, f) = (type_of(cond), type_of(t), type_of(f));
+            if !cond.is_numeric() {
+                problems.push("condition must be numeric");
+            }
+            t.unify(f)
+        }
+        ExprOp::Concat { a, b } => {
+            let (a, b) = (type_of(a), type_of(b));
+            if !matches!(a, ExprType::String | ExprType::Any)
+                || !matches!(b, ExprType::String | ExprType::Any)
+            {
+                problems.push("operands must be strings");
+            }
+            ExprType::String
+        }
+        ExprOp::StrLen { a } => {
+            if !matches!(type_of(a), ExprType::String | ExprType::Any) {
+                problems.push("operand must be a string");
+            }
+            ExprType::Int
+        }
+        ExprOp::Substr { text, start, end } => {
+            let (text, start, end) = (type_of(text), type_of(start), type_of(end));
+            if !matches!(text, ExprType::String | ExprType::Any) {
+                problems.push("text must be a string");
+            }
+            if !matches!(start, ExprType::Int | ExprType::Bool | ExprType::Any)
+                || !matches!(end, ExprType::Int | ExprType::Bool | ExprType::Any)
+            {
+                problems.push("indices must be ints");
+            }
+            ExprType::String
+        }
+        ExprOp::Format { template, .. } => {
+            if !matches!(type_of(template), ExprType::String | ExprType::Any) {
+                problems.push("template must be a string");
+            }
+            ExprType::String
+        }
+    }
+}
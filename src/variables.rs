@@ -0,0 +1,73 @@
+use crate::expressions::{ExprOp, ExprOpRef, ExprPart, ExprPartRef};
+use crate::operation_list::OperationList;
+use std::collections::{BTreeMap, BTreeSet, HashMap};
+
+impl ExprPart {
+    /// The names of the distinct variables of the expression, sorted, i.e. the inputs that must
+    /// be bound to evaluate it.
+    pub fn variables(&self) -> Vec<String> {
+        let names: BTreeSet<&str> = self
+            .post_order()
+            .into_iter()
+            .filter_map(|node| match node {
+                ExprPart::Operation(ExprOp::Var { name }) => Some(name.as_str()),
+                _ => None,
+            })
+            .collect();
+        names.into_iter().map(str::to_string).collect()
+    }
+}
+
+fn var_name(op: &ExprPartRef) -> Option<&str> {
+    match op {
+        ExprPartRef::Operation(ExprOpRef::Var { name }) => Some(name),
+        _ => None,
+    }
+}
+
+impl OperationList {
+    /// The names of the distinct variables of the entries, sorted.
+    pub fn variables(&self) -> Vec<String> {
+        let names: BTreeSet<&str> = self.ops.iter().filter_map(var_name).collect();
+        names.into_iter().map(str::to_string).collect()
+    }
+
+    /// Maps the name of every variable to the ids of the entries that depend on it, in increasing
+    /// order, including the variable's own entries. These are the entries whose values can change
+    /// when the variable changes.
+    pub fn variable_uses(&self) -> BTreeMap<String, Vec<usize>> {
+        let mut indices: HashMap<&str, usize> = HashMap::new();
+        let mut names: Vec<&str> = Vec::new();
+        // The indices of the variables each entry depends on, sorted
+        let mut entry_variables: Vec<Vec<usize>> = Vec::with_capacity(self.ops.len());
+        let mut uses: Vec<Vec<usize>> = Vec::new();
+        for (id, op) in self.ops.iter().enumerate() {
+            let variables = match var_name(op) {
+                Some(name) => {
+                    let index = *indices.entry(name).or_insert_with(|| {
+                        names.push(name);
+                        uses.push(Vec::new());
+                        names.len() - 1
+                    });
+                    vec![index]
+                }
+                None => {
+                    let mut variables: Vec<usize> = op
+                        .children()
+                        .into_iter()
+                        .flat_map(|child| entry_variables[child].iter().copied())
+                        .collect();
+                    variables.sort_unstable();
+                    variables.dedup();
+                    variables
+                }
+            };
+            for &index in &variables {
+                uses[index].push(id);
+            }
+            entry_variables.push(variables);
+        }
+
+        names.into_iter().map(str::to_string).zip(uses).collect()
+    }
+}
@@ -1,4 +1,5 @@
-from simplexp import var, Expr
+from simplexp import var, Expr, Arena, Oplist, set_float_mode, FLOAT_MODE_STRICT, FLOAT_MODE_FAST_MATH, ExprOpId_Add, ExprOpId_Mul
+from simplexp import _lib, _ffi, _last_error
 
 
 def test_simplify(expr):
@@ -19,3 +20,225 @@ print(var('x') - var('x'))
 print('--- SIMPLIFICATION ---')
 test_simplify(var('x') - var('x'))
 test_simplify(var('x') - var('x') + var('y'))
+
+print('--- TYPECHECK ---')
+print(var('x').typecheck())
+print((Expr('hello') + 1).typecheck())
+print(Expr.measure_text_x(5, 12).typecheck())
+
+print('--- FLOAT MODES ---')
+set_float_mode(FLOAT_MODE_STRICT)
+test_simplify(var('x') + 0.0)
+test_simplify(Expr(0.0) / var('x'))
+set_float_mode(FLOAT_MODE_FAST_MATH)
+test_simplify(var('x') + 0.0)
+test_simplify(Expr(0.0) / var('x'))
+
+print('--- INFINITIES ---')
+print(-Expr(float('inf')))
+print(Expr(float('-inf')))
+print(Expr.to_dict(var('x').max(float('-inf'))))
+
+print('--- POW ---')
+print(var('x') ** 0)
+print(var('x') ** 3)
+print((var('x') ** 2) ** 3)
+print(var('x') / 4)
+print(var('x') * var('x') * var('x') * var('x') * var('x') * var('x'))
+print(var('x') * var('x') * var('x') ** 2)
+
+print('--- STRINGS ---')
+print(Expr.format('{} of {}', 3, 10))
+print(Expr.format('{} of {}', var('count'), 10))
+print(Expr('hello').concat(' ').concat('world'))
+print(Expr('hello').str_len())
+print(Expr('hello').substr(1, -1))
+print(Expr(1.0).to_str())
+
+print('--- FACTORING ---')
+print(var('s') * var('x') + var('s') * var('y') + var('s') * var('z'))
+print((var('x') + 1).min(var('x') + 5))
+
+print('--- RATIONALS ---')
+print(var('x') / 3 * 3)
+print(Expr(1) / 3 + Expr(1) / 3 + Expr(1) / 3)
+print(var('x') / 3)
+print(Expr.to_dict(var('x') / 3))
+
+print('--- DEEP EXPRESSIONS ---')
+total = var('w0')
+for i in range(1, 1_000_000):
+    total = total + var(f'w{i}')
+print(len(str(total)))
+print(len(Oplist(total).to_list()))
+print(total.typecheck())
+del total
+arena = Arena()
+shared = arena.var('x')
+for i in range(1_000_000):
+    shared = arena.op(ExprOpId_Add, shared, shared)
+shared = arena.to_expr(shared)
+print(shared.typecheck())
+del shared
+
+print('--- INTERNING ---')
+print(var('x') * var('y') - var('x') * var('y'))
+print((var('x') + var('y')) // 2 * 2 - (var('x') + var('y')) // 2 * 2)
+
+print('--- ARENA ---')
+arena = Arena()
+width = arena.op(ExprOpId_Mul, arena.var('scale'), arena.literal(100))
+total = arena.op(ExprOpId_Add, width, arena.op(ExprOpId_Add, width, arena.literal(0)))
+print(arena.to_expr(total))
+oplist = Oplist()
+print(oplist.append_from_arena(arena, total))
+print(oplist.to_list())
+for invalid in (lambda: arena.op(ExprOpId_Add, 0, 99), lambda: arena.to_expr(99),
+                lambda: oplist.append_from_arena(arena, 99)):
+    try:
+        invalid()
+    except AssertionError as e:
+        print(e)
+print(len(oplist), arena.to_expr(arena.op(ExprOpId_Add, 0, 0)))
+
+print('--- DESERIALIZE ---')
+oplist = Oplist.from_list(Oplist(var('x') / 3 + 1.5).to_list())
+print(oplist.to_list())
+print(oplist.expr(len(oplist.to_list()) - 1))
+try:
+    Oplist.from_list([{'type': 'neg', 'a': 0}])
+except AssertionError as err:
+    print(err)
+
+print('--- COMPACTION ---')
+oplist = Oplist()
+dropped = oplist.append(var('x') * 2)
+kept = oplist.append(var('y') + 1)
+print(oplist.compact([kept]))
+print(oplist.to_list())
+
+print('--- NAMED OUTPUTS ---')
+oplist = Oplist()
+oplist.append_named('rect.left', var('x') + 10)
+oplist.append_named('rect.width', var('w') * 2)
+oplist.append(var('unused'))
+print(oplist.output('rect.left'), oplist.output('rect.width'), oplist.output('rect.top'))
+print(oplist.compact([]))
+print(oplist.to_dict())
+print(Oplist.from_dict(oplist.to_dict()).output('rect.width'))
+
+print('--- OPLIST OPTIMIZATION ---')
+oplist = Oplist.from_list([
+    {'type': 'var', 'name': 'x'}, 2, {'type': 'mul', 'a': 0, 'b': 1}, {'type': 'mul', 'a': 2, 'b': 1},
+    {'type': 'add', 'a': 0, 'b': 0},
+])
+oplist.set_output('scaled', 3)
+print(oplist.optimize())
+print(oplist.compact([]))
+print(oplist.to_dict())
+
+print('--- VALIDATION ---')
+print(Oplist(var('x') + 1).validate())
+
+print('--- PATCHES ---')
+old = Oplist()
+old.append_named('rect.left', var('x') + 10)
+old.append_named('rect.width', var('w') * 2)
+new = Oplist.from_dict(old.to_dict())
+new.append_named('rect.width', var('w') * 3)
+new.compact([])
+new.set_metadata({'screen': 'main'})
+new.serialize_hashes()
+patch = old.diff(new)
+print(patch)
+old.apply_patch(patch)
+print(old.to_dict() == new.to_dict())
+
+print('--- MERGING ---')
+header = Oplist()
+header.append_named('header.height', var('scale') * 40)
+body = Oplist()
+body.append_named('body.top', var('scale') * 40 + 8)
+print(header.merge(body))
+print(header.to_dict())
+
+print('--- STATS ---')
+oplist = Oplist()
+oplist.append_named('rect.left', var('x') + 10)
+oplist.append_named('rect.right', var('x') + 10 + var('width'))
+print(oplist.stats())
+
+print('--- DOT ---')
+width = var('width') * 2
+print((width + width).to_dot(highlight_roots=True))
+before = Oplist()
+before.append_named('rect.left', var('x') + 10)
+after = Oplist.from_dict(before.to_dict())
+after.append_named('rect.right', var('x') + 10 + var('width'))
+print(after.to_dot(before, highlight_roots=True))
+
+print('--- CONTENT HASHES ---')
+first = Oplist(var('x') + 10)
+second = Oplist()
+second.append(var('unrelated'))
+second.append(var('x') + 10)
+print(first.content_hash(2) == second.content_hash(3))
+print(second.find_content_hash(first.content_hash(2)))
+first.serialize_hashes()
+print(first.to_dict())
+print(Oplist.from_dict(first.to_dict()).to_dict() == first.to_dict())
+
+print('--- ENVELOPE ---')
+oplist = Oplist(var('x') + 10)
+oplist.set_metadata({'screen': 'main'})
+serialized = oplist.to_dict()
+print(serialized['formatVersion'], serialized['opSet']['add'], serialized['metadata'])
+print(Oplist.from_dict(serialized).to_dict() == serialized)
+try:
+    Oplist.from_dict(dict(serialized, formatVersion=serialized['formatVersion'] + 1))
+except AssertionError as e:
+    print(e)
+try:
+    Oplist.from_dict(dict(serialized, simplexpVersion='0.0.1', opSet=dict(serialized['opSet'], add=['b', 'a'])))
+except AssertionError as e:
+    print(e)
+
+print('--- EXTRACTION ---')
+oplist = Oplist()
+oplist.append_named('rect.left', var('x') + 10)
+right = oplist.append(var('x') + 10 + var('width'))
+oplist.append_named('label', var('text').to_str())
+widget, ids = oplist.extract([right])
+print(widget.to_list())
+print(widget.output('rect.left'), widget.output('label'))
+print(ids)
+
+print('--- VARIABLES ---')
+print((var('x') + var('width') * var('x')).variables())
+oplist = Oplist()
+oplist.append_named('rect.left', var('x') + 10)
+oplist.append_named('rect.right', var('x') + 10 + var('width'))
+oplist.append_named('title', Expr('Hello'))
+print(oplist.variables())
+print(oplist.variable_uses())
+
+print('--- EVALUATION ---')
+oplist = Oplist()
+oplist.append_named('rect.left', var('x') + 10)
+oplist.append_named('rect.right', var('x') + 10 + var('width'))
+oplist.append_named('ratio', var('width') // 0)
+oplist.append_named('label', Expr.measure_text_x('Hello', 12))
+print(oplist.levels())
+print(oplist.evaluate({'x': 5, 'width': 2.5}, measure_text=lambda text, size: (len(text) * size, size)))
+print(oplist.evaluate({'x': 5, 'width': 2.5}, threads=4, measure_text=lambda text, size: (len(text) * size, size)))
+print(oplist.evaluate({'x': 5, 'width': float('inf')}, threads=4))
+
+print('--- NAN ---')
+try:
+    Expr(float('nan'))
+except ValueError as e:
+    print(e)
+print(_lib.simplexp_float_new(float('nan')) == _ffi.NULL, _last_error())
+print(Expr(0.0) / Expr(0.0))
+print(Expr(float('inf')) - Expr(float('inf')))
+print(Oplist(Expr(float('inf')) - Expr(float('inf'))).evaluate())